@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+
+use crate::config_helper::{get_config_or, get_prompt};
+use crate::metrics;
+use crate::summary;
+
+// ChatGPT tokens used today, counted from the usage block of each completion
+#[derive(Debug, Default, PartialEq)]
+struct DailyUsage {
+    date: Option<NaiveDate>,
+    tokens: u64,
+}
+
+impl DailyUsage {
+    // Midnight in summary.timezone starts a new budget
+    fn roll_over(&mut self, today: NaiveDate) {
+        if self.date != Some(today) {
+            self.date = Some(today);
+            self.tokens = 0;
+        }
+    }
+
+    fn record(&mut self, today: NaiveDate, tokens: u64) {
+        self.roll_over(today);
+        self.tokens += tokens;
+    }
+
+    fn is_exhausted(&mut self, today: NaiveDate, budget: Option<u64>) -> bool {
+        self.roll_over(today);
+        budget.is_some_and(|budget| self.tokens >= budget)
+    }
+}
+
+static USAGE: Lazy<Mutex<DailyUsage>> = Lazy::new(|| Mutex::new(DailyUsage::default()));
+
+fn today() -> NaiveDate {
+    Utc::now().with_timezone(&summary::configured_timezone()).date_naive()
+}
+
+// 0 means no budget
+fn daily_budget() -> Option<u64> {
+    Some(get_config_or("chatgpt.daily_token_budget", 0u64)).filter(|budget| *budget > 0)
+}
+
+pub fn record(tokens: u64) {
+    metrics::increment_by(metrics::CHATGPT_TOKENS_TOTAL, tokens);
+    let mut usage = USAGE.lock().unwrap();
+    let budget = daily_budget();
+    let today = today();
+    let was_exhausted = usage.is_exhausted(today, budget);
+    usage.record(today, tokens);
+    metrics::set_gauge(metrics::CHATGPT_DAILY_TOKENS, usage.tokens);
+
+    if !was_exhausted && usage.is_exhausted(today, budget) {
+        log::warn!("Daily ChatGPT token budget reached: {} tokens used", usage.tokens);
+    }
+}
+
+pub fn is_exhausted() -> bool {
+    USAGE.lock().unwrap().is_exhausted(today(), daily_budget())
+}
+
+// Conversation replies and the summaries they trigger stop here once the budget is spent;
+// the daily summary broadcast still runs
+pub fn guard_message() -> Option<String> {
+    guard(&USAGE, today(), daily_budget())
+}
+
+fn guard(usage: &Mutex<DailyUsage>, today: NaiveDate, budget: Option<u64>) -> Option<String> {
+    usage
+        .lock()
+        .unwrap()
+        .is_exhausted(today, budget)
+        .then(|| get_prompt("prompt.ai_budget_reached"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeded_budget_returns_guard_message() {
+        let usage = Mutex::new(DailyUsage::default());
+        let today = NaiveDate::from_ymd_opt(2026, 10, 16).unwrap();
+
+        usage.lock().unwrap().record(today, 900);
+        assert_eq!(guard(&usage, today, Some(1000)), None);
+
+        usage.lock().unwrap().record(today, 200);
+        assert_eq!(guard(&usage, today, Some(1000)), Some(get_prompt("prompt.ai_budget_reached")));
+        assert_eq!(guard(&usage, today, None), None);
+
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!(guard(&usage, tomorrow, Some(1000)), None);
+        assert_eq!(*usage.lock().unwrap(), DailyUsage { date: Some(tomorrow), tokens: 0 });
+    }
+}
@@ -1,8 +1,20 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
 
-use crate::config_helper::{get_config, get_prompt, get_secret};
-use crate::json;
+use crate::budget;
+use crate::config_helper::{get_config, get_config_or, get_optional_prompt, get_prompt, get_secret};
+use crate::http;
+use crate::readrss::Story;
+use serde_json::json;
 
 #[derive(Debug, Serialize)]
 struct ChatRequest {
@@ -21,6 +33,7 @@ struct ChatMessage {
     content: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct ChatCompletion {
     id: String,
@@ -31,6 +44,7 @@ struct ChatCompletion {
     choices: Vec<Choice>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct Usage {
     prompt_tokens: i32,
@@ -38,6 +52,7 @@ struct Usage {
     total_tokens: i32,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct Choice {
     message: Message,
@@ -45,21 +60,47 @@ struct Choice {
     index: i32,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct Message {
     role: String,
     content: String,
 }
 
-pub async fn run_conversation(content: String) -> Result<String, Box<dyn std::error::Error>> {
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConversationResult {
+    // `message` is any text the model wrote alongside the tool call
+    Tool {
+        name: String,
+        arguments: String,
+        message: Option<String>,
+    },
+    Message(String),
+}
+
+impl ConversationResult {
+    // The shape /conversation has always returned
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ConversationResult::Tool { name, arguments, message } => {
+                let mut tool_choice = json!({"name": name, "arguments": arguments});
+                if let Some(message) = message {
+                    tool_choice["message"] = json!(message);
+                }
+                tool_choice
+            }
+            ConversationResult::Message(message) => json!({"message": message}),
+        }
+    }
+}
+
+pub async fn run_conversation(content: String, stories: &[Story]) -> Result<ConversationResult, Box<dyn std::error::Error>> {
     let api_key = get_secret("chatgpt.secret");
     let url = get_config("chatgpt.chat_completions_url");
-    let model = get_config("chatgpt.model");
+    let model = get_config_or("chatgpt.router_model", get_config("chatgpt.model"));
 
-    let messages = vec![json!({
-        "role": "user",
-        "content": content,
-    })];
+    let story_list = (!stories.is_empty()).then(|| format_stories_for_prompt(&get_prompt("prompt.story_index"), stories));
+    let messages = conversation_messages(get_optional_prompt("prompt.chat_reply"), story_list, content);
 
     let functions = vec![
         json!({
@@ -85,7 +126,7 @@ pub async fn run_conversation(content: String) -> Result<String, Box<dyn std::er
             "type": "function",
             "function": {
             "name": "push_summary",
-            "description": "In the ChatGPT function call, push the selected news summary to the user by index (starting from 1, with a maximum index of 10). The index is passed as an array of integers, with a maximum array size of 5. If the array size exceeds 5, please return error without calling this function.",
+            "description": "In the ChatGPT function call, push the selected news summary to the user by index (the [n] labels of the story list, starting from 1, with a maximum index of 10). The index is passed as an array of integers, with a maximum array size of 5. If the array size exceeds 5, please return error without calling this function.",
             "parameters": {
                 "type": "object",
                 "properties": {
@@ -96,6 +137,14 @@ pub async fn run_conversation(content: String) -> Result<String, Box<dyn std::er
                             "type": "integer"
                           },
                     },
+                    "translate": {
+                        "type": "boolean",
+                        "description": "Whether the summaries should be translated into the user's language. Defaults to true; set it to false when the user asks for the original English summaries.",
+                    },
+                    "include_original": {
+                        "type": "boolean",
+                        "description": "Whether to also send the original English summary after the translation, when the user wants to verify it.",
+                    },
                 },
                 "required": ["indexes"],
             },
@@ -112,6 +161,17 @@ pub async fn run_conversation(content: String) -> Result<String, Box<dyn std::er
                         "type": "string",
                         "description": "An URL of a web page, which content will be summarized and push the summary to user.",
                     },
+                    "languages": {
+                        "type": "array",
+                        "description": "Optional ISO 639-1 language codes (at most 3) when the user asks for the summary in several languages, e.g. [\"en\", \"ja\"]. Omit it to use the default language.",
+                        "items": {
+                            "type": "string"
+                          },
+                    },
+                    "include_original": {
+                        "type": "boolean",
+                        "description": "Whether to also send the original English summary after the translations, when the user wants to verify them.",
+                    },
                 },
                 "required": ["url"],
             },
@@ -129,37 +189,96 @@ pub async fn run_conversation(content: String) -> Result<String, Box<dyn std::er
 
     log::info!("response from function calling: {}", response);
     let response_json: serde_json::Value = serde_json::from_str(&response)?;
-    let function_call = if let Some(choices) = response_json["choices"].as_array() {
-        if let Some(function_call) = choices[0]["message"]["tool_calls"][0]["function"].as_object() {
-            let function_name = function_call["name"].as_str().unwrap();
-            let function_args = function_call["arguments"].as_str().unwrap();
-
-            Some(json!({
-                "name": function_name,
-                "arguments": function_args,
-            }))
-        } else {
-            Some(json!({
-                "message": choices[0]["message"]["content"].as_str().unwrap(),
-            }))
+    let result = match parse_tool_choice(&response_json) {
+        Some(tool_choice) if needs_chat_reply(&tool_choice, get_config_or("chatgpt.separate_chat_reply", false)) => {
+            let chat_model = get_config_or("chatgpt.chat_model", get_config("chatgpt.model"));
+            let payload = build_chat_reply_payload(&chat_model, &messages)?;
+            let response = send_chat_request_json(api_key.as_str(), url.as_str(), payload).await?;
+            let response_json: serde_json::Value = serde_json::from_str(&response)?;
+            parse_tool_choice(&response_json)
         }
-    } else {
-        None
+        tool_choice => tool_choice,
     };
 
-    let tool_choice_json = function_call.unwrap_or(json!({})).to_string();
-    log::info!("function_call: {}", tool_choice_json);
-    Ok(tool_choice_json)
+    let result = result.ok_or("ChatGPT response has no choices")?;
+    log::info!("conversation result: {:?}", result);
+    Ok(result)
+}
+
+// prompt.chat_reply keeps replies that don't call a tool on topic and in the user's language
+fn conversation_messages(chat_prompt: Option<String>, story_list: Option<String>, content: String) -> Vec<serde_json::Value> {
+    let mut messages = Vec::new();
+    if let Some(chat_prompt) = chat_prompt.filter(|prompt| !prompt.trim().is_empty()) {
+        messages.push(json!({"role": "system", "content": chat_prompt}));
+    }
+    if let Some(story_list) = story_list {
+        messages.push(json!({"role": "system", "content": story_list}));
+    }
+    messages.push(json!({"role": "user", "content": content}));
+    messages
+}
+
+// Stories are labelled [n] in the order the user saw them, so "#2" means the same story to the model
+pub fn format_stories_for_prompt(header: &str, stories: &[Story]) -> String {
+    let lines = stories
+        .iter()
+        .enumerate()
+        .map(|(i, story)| format!("[{}] {}", i + 1, story.story))
+        .collect::<Vec<String>>();
+    format!("{}\n{}", header, lines.join("\n"))
+}
+
+// The router model only picks tools; plain chat replies can come from a stronger model
+fn needs_chat_reply(tool_choice: &ConversationResult, separate_chat_reply: bool) -> bool {
+    separate_chat_reply && matches!(tool_choice, ConversationResult::Message(_))
+}
+
+fn build_chat_reply_payload(model: &str, messages: &[serde_json::Value]) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&json!({
+        "model": model,
+        "messages": messages,
+    }))
+}
+
+// The model may explain itself in `content` while also calling a tool, so keep both
+fn parse_tool_choice(response_json: &serde_json::Value) -> Option<ConversationResult> {
+    let message = &response_json["choices"].as_array()?.first()?["message"];
+    let content = message["content"].as_str().unwrap_or_default();
+
+    match message["tool_calls"][0]["function"].as_object() {
+        Some(function_call) => Some(ConversationResult::Tool {
+            name: function_call["name"].as_str()?.to_string(),
+            arguments: function_call["arguments"].as_str().unwrap_or("{}").to_string(),
+            message: Some(content.to_string()).filter(|content| !content.trim().is_empty()),
+        }),
+        None => Some(ConversationResult::Message(content.to_string())),
+    }
 }
 
 pub async fn get_chatgpt_response(prompt_key: &str, content: String, temperature: f64, model_key: &str) -> Result<String, Box<dyn std::error::Error>> {
+    get_chatgpt_response_with_prompt(prompt_key, get_prompt(prompt_key), content, temperature, model_key).await
+}
+
+// prompt_key is the prompt the request was built from, checked against the budget allow-list
+async fn get_chatgpt_response_with_prompt(
+    prompt_key: &str,
+    prompt: String,
+    content: String,
+    temperature: f64,
+    model_key: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
     let api_secret = get_secret("chatgpt.secret");
     let url = get_config("chatgpt.chat_completions_url");
     let model = get_config(model_key);
-    let prompt = get_prompt(prompt_key);
 
-    let request = ChatRequest {
-        model: model.to_owned(),
+    let request = build_chat_request(model, &prompt, &content, temperature);
+    let res_content = send_chat_request(api_secret, url, request, prompt_key).await?;
+    Ok(res_content)
+}
+
+fn build_chat_request(model: String, prompt: &str, content: &str, temperature: f64) -> ChatRequest {
+    ChatRequest {
+        model,
         messages: vec![ChatMessage {
             role: "user".to_owned(),
             content: format!("{} {}", prompt, content),
@@ -169,97 +288,721 @@ pub async fn get_chatgpt_response(prompt_key: &str, content: String, temperature
         top_p: 1.0,
         frequency_penalty: 0.0,
         presence_penalty: 0.0,
-    };
-    let res_content = send_chat_request(api_secret, url, request).await?;
-    Ok(res_content)
+    }
+}
+
+// Writing the summary in the target language reads better than translating it afterwards;
+// None means prompt.summary_all has no {lang} placeholder and the caller has to translate
+pub async fn get_chatgpt_summary_in(stories: String, language_code: &str, prompt_key: &str) -> Option<Result<String, Box<dyn std::error::Error>>> {
+    let prompt = summary_prompt(&get_prompt(prompt_key), language_code)?;
+    Some(get_chatgpt_response_with_prompt(prompt_key, prompt, stories, 0.05, "chatgpt.model").await)
+}
+
+pub async fn get_chatgpt_summary(stories: String, prompt_key: &str) -> Result<String, Box<dyn std::error::Error>> {
+    get_chatgpt_response(prompt_key, stories, 0.05, "chatgpt.model").await
+}
+
+// summary.grouped takes over from summary.style and asks for JSON topic groups
+pub fn configured_summary_prompt() -> &'static str {
+    if get_config_or("summary.grouped", false) {
+        if get_config_or("summary.sentiment_icons", false) {
+            return "prompt.summary_grouped_sentiment";
+        }
+        return "prompt.summary_grouped";
+    }
+    flat_summary_prompt()
+}
+
+// Also what a grouped summary falls back to when its JSON can't be read
+pub fn flat_summary_prompt() -> &'static str {
+    summary_prompt_name(&get_config_or("summary.style", "prose".to_string()))
+}
+
+// summary.style = "bullets" asks for one line per story instead of paragraphs
+fn summary_prompt_name(style: &str) -> &'static str {
+    match style.trim().to_lowercase().as_str() {
+        "bullets" => "prompt.summary_bullets",
+        _ => "prompt.summary_all",
+    }
 }
 
-pub async fn get_chatgpt_summary(stories: String) -> Result<String, Box<dyn std::error::Error>> {
-    get_chatgpt_response("prompt.summary_all", stories, 0.05, "chatgpt.model").await
+fn summary_prompt(template: &str, language_code: &str) -> Option<String> {
+    template
+        .contains("{lang}")
+        .then(|| template.replace("{lang}", language_code))
 }
 
+pub async fn get_article_summary(article: String) -> Result<String, Box<dyn std::error::Error>> {
+    get_chatgpt_response("prompt.summarize_article", article, 0.05, "chatgpt.model").await
+}
+
+const DEFAULT_LANG_CACHE_TTL_SECS: u64 = 60 * 60;
+const LANG_CACHE_CAPACITY: usize = 256;
+
+static LANGUAGE_CACHE: Lazy<Mutex<LanguageCache>> = Lazy::new(|| Mutex::new(LanguageCache::new(LANG_CACHE_CAPACITY)));
+
 pub async fn get_language_code(text: String) -> Result<String, Box<dyn std::error::Error>> {
-    get_chatgpt_response("prompt.get_language_code", text, 0.0, "chatgpt.model").await
+    let ttl = Duration::from_secs(get_config_or("chatgpt.lang_cache_ttl", DEFAULT_LANG_CACHE_TTL_SECS));
+    let language_code = cached_language_code(&LANGUAGE_CACHE, text, ttl, |text| async move {
+        get_chatgpt_response("prompt.get_language_code", text, 0.0, "chatgpt.model")
+            .await
+            .map_err(|e| e.to_string())
+    })
+    .await?;
+    Ok(language_code)
+}
+
+// Quick commands repeat verbatim, so their detected language is remembered for a while
+async fn cached_language_code<F, Fut>(
+    cache: &Mutex<LanguageCache>,
+    text: String,
+    ttl: Duration,
+    detect: F,
+) -> Result<String, String>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+{
+    let key = message_hash(&text);
+    if let Some(language_code) = cache.lock().unwrap().get(key, ttl) {
+        return Ok(language_code);
+    }
+
+    let language_code = detect(text).await?;
+    if !ttl.is_zero() {
+        cache.lock().unwrap().insert(key, language_code.clone());
+    }
+    Ok(language_code)
+}
+
+fn message_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.trim().hash(&mut hasher);
+    hasher.finish()
+}
+
+struct LanguageCache {
+    entries: HashMap<u64, (String, Instant)>,
+    capacity: usize,
+}
+
+impl LanguageCache {
+    fn new(capacity: usize) -> Self {
+        LanguageCache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: u64, ttl: Duration) -> Option<String> {
+        let (language_code, last_used) = self.entries.get_mut(&key)?;
+        if last_used.elapsed() >= ttl {
+            self.entries.remove(&key);
+            return None;
+        }
+        *last_used = Instant::now();
+        Some(language_code.clone())
+    }
+
+    // Evicts the least recently used entry once full
+    fn insert(&mut self, key: u64, language_code: String) {
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            let oldest = self.entries.iter().min_by_key(|(_, (_, last_used))| *last_used).map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (language_code, Instant::now()));
+    }
 }
 
 pub async fn translate(content: String, language_code: String) -> Result<String, Box<dyn std::error::Error>> {
-    let content = format!("{}: {}", language_code, content);
-    get_chatgpt_response("prompt.translate", content, 0.05, "chatgpt.translate_model").await
+    let prompt = translate_prompt(&get_prompt("prompt.translate"), &language_code);
+    get_chatgpt_response_with_prompt("prompt.translate", prompt, content, 0.05, "chatgpt.translate_model").await
+}
+
+const DEFAULT_TRANSLATE_CONCURRENCY: usize = 4;
+
+// Items that fail to translate keep their original text
+pub async fn translate_batch(items: Vec<String>, language_code: String) -> Vec<String> {
+    keep_originals(items.clone(), try_translate_batch(items, language_code).await)
+}
+
+// None marks an item that failed to translate
+async fn try_translate_batch(items: Vec<String>, language_code: String) -> Vec<Option<String>> {
+    let concurrency = get_config_or("chatgpt.translate_concurrency", DEFAULT_TRANSLATE_CONCURRENCY);
+    translate_all(items, concurrency, move |item| {
+        let language_code = language_code.clone();
+        async move {
+            translate(item, language_code).await.map_err(|e| e.to_string())
+        }
+    })
+    .await
+}
+
+// summary.batch: one ChatGPT call translates every summary, answered as a JSON array in the same order;
+// an answer that can't be mapped back falls back to one call per summary
+pub async fn translate_in_one_call(items: Vec<String>, language_code: String) -> Vec<String> {
+    keep_originals(items.clone(), try_translate_in_one_call(items, language_code).await)
+}
+
+pub async fn try_translate_in_one_call(items: Vec<String>, language_code: String) -> Vec<Option<String>> {
+    let prompt = translate_prompt(&get_prompt("prompt.translate_batch"), &language_code);
+    let fallback_language = language_code.clone();
+    translate_combined(
+        items,
+        |content| async move {
+            get_chatgpt_response_with_prompt("prompt.translate_batch", prompt, content, 0.05, "chatgpt.translate_model")
+                .await
+                .map_err(|e| e.to_string())
+        },
+        |items| try_translate_batch(items, fallback_language),
+    )
+    .await
+}
+
+fn keep_originals(items: Vec<String>, translations: Vec<Option<String>>) -> Vec<String> {
+    items
+        .into_iter()
+        .zip(translations)
+        .map(|(item, translation)| translation.unwrap_or(item))
+        .collect()
+}
+
+async fn translate_combined<F, Fut, G, GFut>(items: Vec<String>, request: F, fallback: G) -> Vec<Option<String>>
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+    G: FnOnce(Vec<String>) -> GFut,
+    GFut: Future<Output = Vec<Option<String>>>,
+{
+    if items.len() < 2 {
+        return fallback(items).await;
+    }
+
+    let content = serde_json::to_string(&items).unwrap();
+    match request(content).await.and_then(|response| parse_json_array(&response, items.len())) {
+        Ok(translations) => translations.into_iter().map(Some).collect(),
+        Err(e) => {
+            log::warn!("Batched translation failed, translating one by one: {}", e);
+            fallback(items).await
+        }
+    }
+}
+
+// Models like to wrap JSON in a code fence or a sentence, so only the outermost array is read
+fn parse_json_array(response: &str, expected_len: usize) -> Result<Vec<String>, String> {
+    let start = response.find('[').ok_or("no JSON array in the response")?;
+    let end = response.rfind(']').filter(|end| *end > start).ok_or("no JSON array in the response")?;
+    let items: Vec<String> = serde_json::from_str(&response[start..=end]).map_err(|e| e.to_string())?;
+    if items.len() != expected_len {
+        return Err(format!("expected {} items, got {}", expected_len, items.len()));
+    }
+    Ok(items)
+}
+
+async fn translate_all<F, Fut>(items: Vec<String>, concurrency: usize, translate_item: F) -> Vec<Option<String>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+{
+    stream::iter(items)
+        .map(|item| {
+            let translation = translate_item(item);
+            async move {
+                translation
+                    .await
+                    .map_err(|e| log::warn!("Translation failed: {}", e))
+                    .ok()
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+// The target language goes into the instruction so the model doesn't echo a tag in the content
+fn translate_prompt(template: &str, language_code: &str) -> String {
+    if template.contains("{lang}") {
+        template.replace("{lang}", language_code)
+    } else {
+        format!("{} {}:", template.trim_end(), language_code)
+    }
+}
+
+pub const BUDGET_REACHED: &str = "daily ChatGPT token budget reached";
+
+// Only the daily summary broadcast keeps calling ChatGPT once the token budget is spent
+const BUDGET_EXEMPT_PROMPTS: &[&str] = &[
+    "prompt.summary_all",
+    "prompt.summary_bullets",
+    "prompt.summary_grouped",
+    "prompt.summary_grouped_sentiment",
+];
+
+fn is_budget_exempt(prompt_key: &str) -> bool {
+    BUDGET_EXEMPT_PROMPTS.contains(&prompt_key)
 }
 
 async fn send_chat_request(
     api_secret: String,
     url: String,
     request: ChatRequest,
+    prompt_key: &str,
 ) -> Result<String, Box<dyn std::error::Error>> {
+    if !is_budget_exempt(prompt_key) && budget::is_exhausted() {
+        return Err(BUDGET_REACHED.into());
+    }
+
     let client = reqwest::Client::new();
     let json_body = serde_json::to_string(&request)?;
 
-    let response = client
-        .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(AUTHORIZATION, format!("Bearer {}", api_secret))
-        .body(json_body)
-        .send()
-        .await?;
+    let response = http::send(
+        client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", api_secret))
+            .body(json_body),
+    )
+    .await?;
     let response_text = response.text().await?;
     let response_struct: ChatCompletion = serde_json::from_str(&response_text)?;
+    budget::record(response_struct.usage.total_tokens.max(0) as u64);
 
     let res_content = response_struct.choices[0].message.content.clone();
 
     Ok(res_content)
 }
 
+const DEFAULT_MODERATION_URL: &str = "https://api.openai.com/v1/moderations";
+const MODERATION_SNIPPET_CHARS: usize = 2000;
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    #[serde(default)]
+    pub categories: HashMap<String, bool>,
+}
+
+impl ModerationResult {
+    pub fn flagged_categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = self
+            .categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.as_str())
+            .collect();
+        categories.sort();
+        categories
+    }
+}
+
+pub async fn moderate(text: &str) -> Result<ModerationResult, Box<dyn std::error::Error + Send + Sync>> {
+    let api_key = get_secret("chatgpt.secret");
+    let url = get_config_or("moderation.url", DEFAULT_MODERATION_URL.to_string());
+    request_moderation(&api_key, &url, text).await
+}
+
+// With moderation.enabled, text is checked before it is summarized; a failed check lets it through
+pub async fn is_flagged(text: &str) -> bool {
+    if !get_config_or("moderation.enabled", false) {
+        return false;
+    }
+
+    let snippet: String = text.chars().take(MODERATION_SNIPPET_CHARS).collect();
+    match moderate(&snippet).await {
+        Ok(result) if result.flagged => {
+            log::warn!("Moderation flagged content: {:?}", result.flagged_categories());
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            log::warn!("Moderation check failed, allowing the content: {}", e);
+            false
+        }
+    }
+}
+
+async fn request_moderation(api_key: &str, url: &str, text: &str) -> Result<ModerationResult, Box<dyn std::error::Error + Send + Sync>> {
+    let response = http::send(
+        reqwest::Client::new()
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", api_key))
+            .body(json!({"input": text}).to_string()),
+    )
+    .await?
+    .error_for_status()?
+    .text()
+    .await?;
+
+    let response: ModerationResponse = serde_json::from_str(&response)?;
+    response.results.into_iter().next().ok_or_else(|| "moderation response has no results".into())
+}
+
 async fn send_chat_request_json(
     api_secret: &str,
     url: &str,
     payload: String,
 ) -> Result<String, Box<dyn std::error::Error>> {
+    if budget::is_exhausted() {
+        return Err(BUDGET_REACHED.into());
+    }
+
     let client = reqwest::Client::new();
 
-    let res = client
-        .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(AUTHORIZATION, format!("Bearer {}", api_secret))
-        .body(payload)
-        .send().await?;
-    Ok(res.text().await?)
+    let res = http::send(
+        client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", api_secret))
+            .body(payload),
+    )
+    .await?;
+    let response = res.text().await?;
+    if let Some(tokens) = total_tokens(&response) {
+        budget::record(tokens);
+    }
+    Ok(response)
+}
+
+fn total_tokens(response: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(response).ok()?["usage"]["total_tokens"].as_u64()
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
     use serde_json::Value;
     use super::*;
 
+    #[tokio::test]
+    async fn test_moderation_flags_content() {
+        use warp::Filter;
+
+        let moderations = warp::post()
+            .and(warp::path("moderations"))
+            .and(warp::body::json())
+            .map(|body: Value| {
+                let flagged = body["input"].as_str().unwrap().contains("violent");
+                warp::reply::json(&json!({
+                    "id": "modr-1",
+                    "results": [{"flagged": flagged, "categories": {"violence": flagged, "hate": false}}]
+                }))
+            });
+        let (addr, server) = warp::serve(moderations).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/moderations", addr);
+
+        let result = request_moderation("key", &url, "a violent threat").await.unwrap();
+        assert!(result.flagged);
+        assert_eq!(result.flagged_categories(), vec!["violence"]);
+
+        let result = request_moderation("key", &url, "A new Rust release").await.unwrap();
+        assert!(!result.flagged);
+    }
+
+    #[tokio::test]
+    async fn test_batched_translation_maps_back_by_index() {
+        let items = vec!["First summary.".to_string(), "Second summary.".to_string()];
+
+        let translations = translate_combined(
+            items.clone(),
+            |content| async move {
+                assert_eq!(content, r#"["First summary.","Second summary."]"#);
+                Ok("```json\n[\"第一則摘要。\", \"第二則摘要。\"]\n```".to_string())
+            },
+            |_| async { panic!("should not fall back") },
+        )
+        .await;
+        assert_eq!(translations, vec![Some("第一則摘要。".to_string()), Some("第二則摘要。".to_string())]);
+
+        let translations = translate_combined(
+            items,
+            |_| async { Ok(r#"["只有一則"]"#.to_string()) },
+            |items| async move { items.iter().map(|item| Some(format!("one by one: {}", item))).collect() },
+        )
+        .await;
+        assert_eq!(
+            keep_originals(vec!["First summary.".to_string(), "Second summary.".to_string()], translations),
+            vec!["one by one: First summary.", "one by one: Second summary."]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_message_hits_language_cache() {
+        let cache = Mutex::new(LanguageCache::new(2));
+        let calls = AtomicUsize::new(0);
+        let detect = |_text: String| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Ok("zh-TW".to_string()) }
+        };
+
+        let ttl = Duration::from_secs(60);
+        let first = cached_language_code(&cache, "今日新聞".to_string(), ttl, detect).await.unwrap();
+        let second = cached_language_code(&cache, "今日新聞 ".to_string(), ttl, detect).await.unwrap();
+
+        assert_eq!(first, "zh-TW");
+        assert_eq!(second, "zh-TW");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_language_cache_evicts_least_recently_used() {
+        let mut cache = LanguageCache::new(2);
+        let ttl = Duration::from_secs(60);
+        cache.insert(1, "en".to_string());
+        cache.insert(2, "ja".to_string());
+        assert_eq!(cache.get(1, ttl), Some("en".to_string()));
+
+        cache.insert(3, "ko".to_string());
+
+        assert_eq!(cache.get(2, ttl), None);
+        assert_eq!(cache.get(1, ttl), Some("en".to_string()));
+        assert_eq!(cache.get(3, Duration::ZERO), None);
+    }
+
+    #[tokio::test]
+    async fn test_translate_all_preserves_order() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let items = vec!["slow".to_string(), "fails".to_string(), "fast".to_string()];
+        let items_copy = items.clone();
+
+        let translations = translate_all(items, 3, |item| {
+            let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                let delay = if item == "slow" { 50 } else { 10 };
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                match item.as_str() {
+                    "fails" => Err("rate limited".to_string()),
+                    _ => Ok(format!("{} (ja)", item)),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(translations, vec![Some("slow (ja)".to_string()), None, Some("fast (ja)".to_string())]);
+        assert_eq!(keep_originals(items_copy, translations), vec!["slow (ja)", "fails", "fast (ja)"]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_summary_language_reaches_payload() {
+        let prompt = summary_prompt(&get_prompt("prompt.summary_all"), "ja").unwrap();
+        let request = build_chat_request("gpt-4o".to_string(), &prompt, "1. Story (https://example.com)", 0.05);
+
+        let payload = serde_json::to_value(&request).unwrap();
+        let content = payload["messages"][0]["content"].as_str().unwrap();
+
+        assert!(content.contains("always response in ja"));
+        assert!(!content.contains("{lang}"));
+        assert!(summary_prompt("Summarize in English:", "ja").is_none());
+    }
+
+    #[test]
+    fn test_summary_style_selects_prompt() {
+        assert_eq!(summary_prompt_name("Bullets"), "prompt.summary_bullets");
+        assert_eq!(summary_prompt_name("prose"), "prompt.summary_all");
+        assert!(get_prompt(summary_prompt_name("bullets")).contains("{lang}"));
+        assert!(get_prompt("prompt.summary_grouped").contains("{lang}"));
+        assert!(get_prompt("prompt.summary_grouped_sentiment").contains("{lang}"));
+    }
+
+    #[test]
+    fn test_only_daily_summary_prompts_skip_the_budget() {
+        for style in ["prose", "bullets"] {
+            assert!(is_budget_exempt(summary_prompt_name(style)));
+        }
+        assert!(is_budget_exempt("prompt.summary_grouped"));
+        assert!(is_budget_exempt("prompt.summary_grouped_sentiment"));
+
+        for prompt_key in ["prompt.summarize_article", "prompt.translate", "prompt.translate_batch", "prompt.get_language_code"] {
+            assert!(!is_budget_exempt(prompt_key));
+        }
+    }
+
+    #[test]
+    fn test_translate_prompt_substitutes_language() {
+        let prompt = translate_prompt(&get_prompt("prompt.translate"), "ja");
+        let content = "An English summary.";
+        let message = format!("{} {}", prompt, content);
+
+        assert_eq!(message.matches("ja").count(), 1);
+        assert!(!prompt.contains("{lang}"));
+        assert!(message.ends_with(": An English summary."));
+        assert!(!message.contains("ja: An English summary."));
+    }
+
     #[tokio::test]
     async fn test_run_conversation() {
         let content = "第一, 第二, 第三".to_string();
-        let result = run_conversation(content).await.unwrap();
-        println!("result: {}", result);
-        let expected_result = r#"{"arguments":"{\n  \"indexes\": [1,2,3]\n}","name":"push_summary"}"#;
+        let result = run_conversation(content, &[]).await.unwrap();
+        println!("result: {:?}", result);
+        let expected_result = ConversationResult::Tool {
+            name: "push_summary".to_string(),
+            arguments: "{\n  \"indexes\": [1,2,3]\n}".to_string(),
+            message: None,
+        };
         assert_eq!(result, expected_result);
     }
 
     #[tokio::test]
     async fn test_url_summary() {
         let url = "https://www.apple.com/apple-music/".to_string();
-        let result = run_conversation(url).await.unwrap();
-        println!("result: {}", result);
-        let json: Result<Value, _> = serde_json::from_str(result.as_str());
-        let url = match json {
-            Ok(data) => {
-                let arguments = data.get("arguments").unwrap().as_str().unwrap();
+        let result = run_conversation(url, &[]).await.unwrap();
+        println!("result: {:?}", result);
+        let url = match result {
+            ConversationResult::Tool { arguments, .. } => {
                 println!("arguments_str: {}", arguments);
-                let arguments_json: Value = serde_json::from_str(arguments).unwrap();
-                let url_str = arguments_json.get("url").unwrap().as_str().unwrap().to_string();
-                url_str
-            },
-            Err(e) => panic!("Problem with JSON: {:?}", e),
+                let arguments_json: Value = serde_json::from_str(&arguments).unwrap();
+                arguments_json.get("url").unwrap().as_str().unwrap().to_string()
+            }
+            ConversationResult::Message(message) => panic!("Expected a tool call, got {:?}", message),
         };
         let expected_result = r#"https://www.apple.com/apple-music/"#;
         assert_eq!(url, expected_result);
     }
 
+    #[test]
+    fn test_parse_tool_choice_keeps_content_and_tool_call() {
+        let response: Value = serde_json::from_str(r#"{
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": "Here are the summaries you asked for.",
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "push_summary", "arguments": "{\"indexes\": [1]}"}
+                    }]
+                },
+                "finish_reason": "tool_calls",
+                "index": 0
+            }]
+        }"#).unwrap();
+
+        let tool_choice = parse_tool_choice(&response).unwrap();
+
+        assert_eq!(
+            tool_choice,
+            ConversationResult::Tool {
+                name: "push_summary".to_string(),
+                arguments: "{\"indexes\": [1]}".to_string(),
+                message: Some("Here are the summaries you asked for.".to_string()),
+            }
+        );
+        assert_eq!(tool_choice.to_json()["message"], "Here are the summaries you asked for.");
+    }
+
+    #[test]
+    fn test_parse_tool_choice_without_content() {
+        let response: Value = serde_json::from_str(r#"{
+            "choices": [{
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {"name": "reply_latest_story", "arguments": "{}"}
+                    }]
+                }
+            }]
+        }"#).unwrap();
+
+        let tool_choice = parse_tool_choice(&response).unwrap();
+
+        assert_eq!(
+            tool_choice,
+            ConversationResult::Tool {
+                name: "reply_latest_story".to_string(),
+                arguments: "{}".to_string(),
+                message: None,
+            }
+        );
+        assert!(tool_choice.to_json().get("message").is_none());
+    }
+
+    #[test]
+    fn test_parse_tool_choice_blank_content_is_no_message() {
+        let response = json!({"choices": [{"message": {
+            "content": "  ",
+            "tool_calls": [{"function": {"name": "push_summary", "arguments": "{}"}}]
+        }}]});
+
+        let Some(ConversationResult::Tool { message, .. }) = parse_tool_choice(&response) else {
+            panic!("expected a tool call");
+        };
+        assert_eq!(message, None);
+        assert_eq!(
+            parse_tool_choice(&json!({"choices": [{"message": {"content": "Hi!"}}]})),
+            Some(ConversationResult::Message("Hi!".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tool_selected_path_skips_chat_reply() {
+        let tool_choice = ConversationResult::Tool {
+            name: "push_summary".to_string(),
+            arguments: "{\"indexes\": [1]}".to_string(),
+            message: None,
+        };
+
+        assert!(!needs_chat_reply(&tool_choice, true));
+    }
+
+    #[test]
+    fn test_chat_reply_path_uses_chat_model() {
+        let tool_choice = ConversationResult::Message("Hi there!".to_string());
+        assert!(needs_chat_reply(&tool_choice, true));
+        assert!(!needs_chat_reply(&tool_choice, false));
+
+        let messages = vec![json!({"role": "user", "content": "hello"})];
+        let payload: Value = serde_json::from_str(&build_chat_reply_payload("gpt-4o", &messages).unwrap()).unwrap();
+
+        assert_eq!(payload["model"], "gpt-4o");
+        assert_eq!(payload["messages"][0]["content"], "hello");
+    }
+
+    #[test]
+    fn test_story_list_prompt_has_explicit_indices() {
+        let stories = vec![
+            Story { storylink: "https://example.com/1".to_string(), story: "Rust 2.0 released".to_string(), published: None },
+            Story { storylink: "https://example.com/2".to_string(), story: "A new SQLite release".to_string(), published: None },
+        ];
+
+        let story_list = format_stories_for_prompt("Indexes start at 1:", &stories);
+        assert_eq!(story_list, "Indexes start at 1:\n[1] Rust 2.0 released\n[2] A new SQLite release");
+
+        let messages = conversation_messages(None, Some(story_list.clone()), "summarize #2".to_string());
+        assert_eq!(messages[0], json!({"role": "system", "content": story_list}));
+        assert_eq!(messages[1], json!({"role": "user", "content": "summarize #2"}));
+    }
+
+    #[test]
+    fn test_chat_reply_path_uses_chat_prompt() {
+        let messages = conversation_messages(get_optional_prompt("prompt.chat_reply"), None, "你好".to_string());
+        let payload: Value = serde_json::from_str(&build_chat_reply_payload("gpt-4o", &messages).unwrap()).unwrap();
+
+        assert_eq!(payload["messages"][0]["role"], "system");
+        assert_eq!(payload["messages"][0]["content"], get_prompt("prompt.chat_reply"));
+        assert_eq!(payload["messages"][1], json!({"role": "user", "content": "你好"}));
+        assert_eq!(conversation_messages(None, None, "hi".to_string()).len(), 1);
+        assert!(payload.get("tools").is_none());
+    }
+
     #[tokio::test]
     async fn test_get_language_code() {
         let text = "Hello, world!".to_string();
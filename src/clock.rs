@@ -0,0 +1,62 @@
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+
+// Time-dependent logic reads the time through a Clock so tests can move it forward
+pub trait Clock: Send + Sync {
+    fn now_utc(&self) -> DateTime<Utc>;
+    fn now_instant(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use chrono::{DateTime, Utc};
+
+    use super::Clock;
+
+    pub struct MockClock {
+        now: Mutex<(DateTime<Utc>, Instant)>,
+    }
+
+    impl MockClock {
+        pub fn new(now_utc: DateTime<Utc>) -> Self {
+            MockClock {
+                now: Mutex::new((now_utc, Instant::now())),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            let mut now = self.now.lock().unwrap();
+            now.0 += chrono::Duration::from_std(duration).unwrap();
+            now.1 += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now_utc(&self) -> DateTime<Utc> {
+            self.now.lock().unwrap().0
+        }
+
+        fn now_instant(&self) -> Instant {
+            self.now.lock().unwrap().1
+        }
+    }
+}
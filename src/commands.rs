@@ -0,0 +1,121 @@
+use crate::config_helper::get_config_or;
+
+const DEFAULT_LATEST_STORIES_KEYWORDS: [&str; 6] = ["today", "latest", "news", "today's news", "今日新聞", "最新新聞"];
+const DEFAULT_DAILY_SUMMARY_KEYWORDS: [&str; 5] = ["summary", "summarize all", "daily summary", "摘要", "今日摘要"];
+const DEFAULT_HELP_KEYWORDS: [&str; 2] = ["help", "說明"];
+const DEFAULT_HELP_CAPABILITIES: [&str; 4] = [
+    "latest stories: list today's Hacker News stories",
+    "summarize #N: summarize story number N",
+    "summarize <url>: summarize any article",
+    "daily summary: one summary of today's stories",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    LatestStories,
+    DailySummary,
+    Help,
+}
+
+pub struct CommandKeywords {
+    pub latest_stories: Vec<String>,
+    pub daily_summary: Vec<String>,
+    pub help: Vec<String>,
+}
+
+impl CommandKeywords {
+    pub fn from_config() -> Self {
+        CommandKeywords {
+            latest_stories: get_config_or("commands.latest_stories", to_strings(&DEFAULT_LATEST_STORIES_KEYWORDS)),
+            daily_summary: get_config_or("commands.daily_summary", to_strings(&DEFAULT_DAILY_SUMMARY_KEYWORDS)),
+            help: get_config_or("commands.help", to_strings(&DEFAULT_HELP_KEYWORDS)),
+        }
+    }
+}
+
+pub fn help_capabilities() -> Vec<String> {
+    get_config_or("commands.help_capabilities", to_strings(&DEFAULT_HELP_CAPABILITIES))
+}
+
+fn to_strings(keywords: &[&str]) -> Vec<String> {
+    keywords.iter().map(|keyword| keyword.to_string()).collect()
+}
+
+// Obvious commands are answered directly, everything else goes through ChatGPT routing
+pub fn parse(text: &str) -> Option<Command> {
+    parse_with_keywords(text, &CommandKeywords::from_config())
+}
+
+pub fn parse_with_keywords(text: &str, keywords: &CommandKeywords) -> Option<Command> {
+    let text = normalize(text);
+    if text.is_empty() {
+        return None;
+    }
+
+    let matches = |candidates: &[String]| candidates.iter().any(|keyword| normalize(keyword) == text);
+    if matches(&keywords.latest_stories) {
+        Some(Command::LatestStories)
+    } else if matches(&keywords.daily_summary) {
+        Some(Command::DailySummary)
+    } else if matches(&keywords.help) {
+        Some(Command::Help)
+    } else {
+        None
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.trim()
+        .trim_end_matches(['!', '?', '.', '！', '？', '。'])
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn default_keywords() -> CommandKeywords {
+        CommandKeywords {
+            latest_stories: to_strings(&DEFAULT_LATEST_STORIES_KEYWORDS),
+            daily_summary: to_strings(&DEFAULT_DAILY_SUMMARY_KEYWORDS),
+            help: to_strings(&DEFAULT_HELP_KEYWORDS),
+        }
+    }
+
+    #[test]
+    fn test_parse_matched_phrases() {
+        let keywords = default_keywords();
+
+        assert_eq!(parse_with_keywords("Today's news", &keywords), Some(Command::LatestStories));
+        assert_eq!(parse_with_keywords("  latest!", &keywords), Some(Command::LatestStories));
+        assert_eq!(parse_with_keywords("今日新聞", &keywords), Some(Command::LatestStories));
+        assert_eq!(parse_with_keywords("Summarize  all", &keywords), Some(Command::DailySummary));
+        assert_eq!(parse_with_keywords("摘要？", &keywords), Some(Command::DailySummary));
+        assert_eq!(parse_with_keywords("Help", &keywords), Some(Command::Help));
+        assert_eq!(parse_with_keywords("說明", &keywords), Some(Command::Help));
+    }
+
+    #[test]
+    fn test_parse_unmatched_phrase() {
+        let keywords = default_keywords();
+
+        assert_eq!(parse_with_keywords("summarize https://example.com/post", &keywords), None);
+        assert_eq!(parse_with_keywords("第一, 第二, 第三", &keywords), None);
+        assert_eq!(parse_with_keywords("", &keywords), None);
+    }
+
+    #[test]
+    fn test_parse_with_custom_keywords() {
+        let keywords = CommandKeywords {
+            latest_stories: vec!["ニュース".to_string()],
+            daily_summary: Vec::new(),
+            help: Vec::new(),
+        };
+
+        assert_eq!(parse_with_keywords("ニュース", &keywords), Some(Command::LatestStories));
+        assert_eq!(parse_with_keywords("today", &keywords), None);
+    }
+}
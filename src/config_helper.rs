@@ -1,4 +1,60 @@
+use std::fmt;
+
 use config::{Config, File, FileFormat};
+use serde::de::DeserializeOwned;
+
+const REQUIRED_SECRETS: [&str; 4] = ["channel.secret", "channel.token", "chatgpt.secret", "kagi.token"];
+
+const CONFIGURED_URLS: [&str; 6] = [
+    "message.broadcast_url",
+    "message.reply_url",
+    "message.push_url",
+    "chatgpt.chat_completions_url",
+    "kagi.kagi_summarize_url",
+    "rss.feed_url",
+];
+
+#[derive(Debug)]
+pub struct ConfigStatus {
+    pub secrets: Vec<(String, bool)>,
+    pub urls: Vec<(String, Option<String>)>,
+    pub model: Option<String>,
+    pub translate_model: Option<String>,
+}
+
+impl ConfigStatus {
+    pub fn is_ready(&self) -> bool {
+        self.secrets.iter().all(|(_, present)| *present)
+            && self.urls.iter().all(|(_, url)| url.is_some())
+            && self.model.is_some()
+    }
+}
+
+impl fmt::Display for ConfigStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secrets = self
+            .secrets
+            .iter()
+            .map(|(name, present)| format!("{}={}", name, if *present { "present" } else { "MISSING" }))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let urls = self
+            .urls
+            .iter()
+            .map(|(name, url)| format!("{}={}", name, url.as_deref().unwrap_or("MISSING")))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(
+            f,
+            "secrets: [{}]; urls: [{}]; model: {}; translate_model: {}",
+            secrets,
+            urls,
+            self.model.as_deref().unwrap_or("MISSING"),
+            self.translate_model.as_deref().unwrap_or("MISSING"),
+        )
+    }
+}
 
 pub fn get_config_by_file(config_name: &str, config_file: &str) -> String {
     let config_builder = Config::builder().add_source(File::new(config_file, FileFormat::Toml));
@@ -7,16 +63,75 @@ pub fn get_config_by_file(config_name: &str, config_file: &str) -> String {
     config_value
 }
 
+pub fn get_optional_config_by_file(config_name: &str, config_file: &str) -> Option<String> {
+    let config_builder = Config::builder().add_source(File::new(config_file, FileFormat::Toml));
+
+    config_builder.build().ok()?.get::<String>(config_name).ok()
+}
+
 pub fn get_config(config_name: &str) -> String {
-    return get_config_by_file(config_name, "config.toml");
+    get_config_by_file(config_name, "config.toml")
+}
+
+pub fn get_optional_config(config_name: &str) -> Option<String> {
+    get_optional_config_by_file(config_name, "config.toml")
+}
+
+pub fn get_config_or<T: DeserializeOwned>(config_name: &str, default: T) -> T {
+    let config_builder = Config::builder().add_source(File::new("config.toml", FileFormat::Toml));
+
+    config_builder
+        .build()
+        .ok()
+        .and_then(|config| config.get::<T>(config_name).ok())
+        .unwrap_or(default)
 }
 
 pub fn get_secret(secret_name: &str) -> String {
-    return get_config_by_file(secret_name, "secrets.toml");
+    get_config_by_file(secret_name, "secrets.toml")
 }
 
 pub fn get_prompt(prompt: &str) -> String {
-    return get_config_by_file(prompt, "prompts.toml");
+    get_config_by_file(prompt, "prompts.toml")
+}
+
+pub fn get_optional_prompt(prompt: &str) -> Option<String> {
+    get_optional_config_by_file(prompt, "prompts.toml")
+}
+
+pub fn report_status() -> ConfigStatus {
+    report_status_by_file("config.toml", "secrets.toml")
+}
+
+pub fn report_status_by_file(config_file: &str, secrets_file: &str) -> ConfigStatus {
+    let secrets = REQUIRED_SECRETS
+        .iter()
+        .map(|name| {
+            let present = get_optional_config_by_file(name, secrets_file)
+                .map(|value| is_secret_present(&value))
+                .unwrap_or(false);
+            (name.to_string(), present)
+        })
+        .collect();
+
+    let urls = CONFIGURED_URLS
+        .iter()
+        .map(|name| (name.to_string(), get_optional_config_by_file(name, config_file)))
+        .collect();
+
+    ConfigStatus {
+        secrets,
+        urls,
+        model: get_optional_config_by_file("chatgpt.model", config_file),
+        translate_model: get_optional_config_by_file("chatgpt.translate_model", config_file),
+    }
+}
+
+// Empty values and the `<YOUR ...>` placeholders from the sample secrets.toml count as missing.
+fn is_secret_present(value: &str) -> bool {
+    let value = value.trim();
+    let is_placeholder = value.starts_with('<') && value.ends_with('>');
+    !value.is_empty() && !is_placeholder
 }
 
 #[cfg(test)]
@@ -29,7 +144,7 @@ mod tests {
         let config_value = get_config("chatgpt.model");
 
         // Assert that the returned value is correct
-        assert_eq!(config_value, "gpt-4");
+        assert_eq!(config_value, "gpt-4o");
     }
 
     #[test]
@@ -40,4 +155,29 @@ mod tests {
         // Assert that the returned value is not None or an empty string
         assert!(!prompt_value.is_empty(), "Prompt value is empty");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_report_status_marks_missing_secret_absent() {
+        let secrets_file = std::env::temp_dir().join(format!("secrets-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &secrets_file,
+            "[channel]\nsecret = \"channel-secret-value\"\ntoken = \"<YOUR CHANNEL TOKEN>\"\n\n[chatgpt]\nsecret = \"sk-value\"\n",
+        )
+        .unwrap();
+
+        let status = report_status_by_file("config.toml", secrets_file.to_str().unwrap());
+        std::fs::remove_file(&secrets_file).unwrap();
+
+        let present = |name: &str| status.secrets.iter().find(|(n, _)| n == name).unwrap().1;
+        assert!(present("channel.secret"));
+        assert!(present("chatgpt.secret"));
+        assert!(!present("channel.token"));
+        assert!(!present("kagi.token"));
+        assert!(!status.is_ready());
+
+        let report = status.to_string();
+        assert!(report.contains("kagi.token=MISSING"));
+        assert!(!report.contains("channel-secret-value"));
+        assert!(!report.contains("sk-value"));
+    }
+}
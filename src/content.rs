@@ -0,0 +1,172 @@
+use std::error::Error;
+
+use scraper::{ElementRef, Html, Selector};
+
+use crate::http;
+
+const WORDS_PER_MINUTE: usize = 200;
+
+const SKIPPED_ANCESTORS: [&str; 7] = ["nav", "header", "footer", "aside", "script", "style", "noscript"];
+
+pub async fn fetch_article_text(url: &str) -> Result<String, Box<dyn Error>> {
+    let html = http::send(reqwest::Client::new().get(url)).await?.error_for_status()?.text().await?;
+    let text = extract_article_text(&html);
+    if text.is_empty() {
+        return Err(format!("No article text found at {}", url).into());
+    }
+    Ok(text)
+}
+
+pub fn extract_article_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let article_selector = Selector::parse("article p").unwrap();
+    let paragraph_selector = Selector::parse("p").unwrap();
+
+    let mut paragraphs = collect_paragraphs(&document, &article_selector);
+    if paragraphs.is_empty() {
+        paragraphs = collect_paragraphs(&document, &paragraph_selector);
+    }
+    paragraphs.join("\n\n")
+}
+
+fn collect_paragraphs(document: &Html, selector: &Selector) -> Vec<String> {
+    document
+        .select(selector)
+        .filter(|paragraph| !has_skipped_ancestor(paragraph))
+        .map(|paragraph| {
+            paragraph
+                .text()
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        })
+        .filter(|text| !text.is_empty())
+        .collect()
+}
+
+fn has_skipped_ancestor(element: &ElementRef) -> bool {
+    element
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .any(|ancestor| SKIPPED_ANCESTORS.contains(&ancestor.value().name()))
+}
+
+// Rough budget of four characters per token
+pub fn truncate_to_token_budget(text: &str, max_tokens: usize) -> String {
+    truncate_chars(text, max_tokens * 4)
+}
+
+// Same rough four characters per token as truncate_to_token_budget
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+// Rounded up, so even a short post reads as one minute
+pub fn estimate_reading_minutes(text: &str) -> u32 {
+    let words = text.split_whitespace().count();
+    words.div_ceil(WORDS_PER_MINUTE).max(1) as u32
+}
+
+// Keeps paragraph breaks but never more than one blank line in a row, and no trailing spaces
+pub fn clean_summary(summary: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in summary.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+// Cuts on a char boundary so multi-byte text stays valid UTF-8
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((byte_index, _)) => {
+            log::info!(
+                "Truncated article text from {} to {} characters",
+                text.chars().count(),
+                max_chars
+            );
+            text[..byte_index].to_string()
+        }
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_PAGE: &str = r#"
+        <html>
+            <head><script>var tracking = "ignored";</script></head>
+            <body>
+                <nav><p>Home | About | Contact</p></nav>
+                <article>
+                    <h1>Rust 2.0 announced</h1>
+                    <p>The Rust team   announced
+                       a new edition.</p>
+                    <p>It focuses on <b>async</b> ergonomics.</p>
+                    <aside><p>Related: other news</p></aside>
+                </article>
+                <footer><p>Copyright</p></footer>
+            </body>
+        </html>
+    "#;
+
+    #[test]
+    fn test_extract_article_text() {
+        let text = extract_article_text(SAMPLE_PAGE);
+
+        assert_eq!(
+            text,
+            "The Rust team announced a new edition.\n\nIt focuses on async ergonomics."
+        );
+    }
+
+    #[test]
+    fn test_extract_article_text_without_article_tag() {
+        let html = "<html><body><nav><p>Menu</p></nav><div><p>Body text.</p></div></body></html>";
+
+        assert_eq!(extract_article_text(html), "Body text.");
+    }
+
+    #[test]
+    fn test_truncate_chars_at_boundary() {
+        let text = "ab€cd漢字";
+
+        assert_eq!(truncate_chars(text, 3), "ab€");
+        assert_eq!(truncate_chars(text, 6), "ab€cd漢");
+        assert_eq!(truncate_chars(text, 7), text);
+        assert_eq!(truncate_chars(text, 100), text);
+        assert_eq!(truncate_chars(text, 0), "");
+    }
+
+    #[test]
+    fn test_truncate_to_token_budget() {
+        assert_eq!(truncate_to_token_budget("abcdefghij", 2), "abcdefgh");
+        assert_eq!(truncate_to_token_budget("短文", 10), "短文");
+    }
+
+    #[test]
+    fn test_estimate_reading_minutes() {
+        assert_eq!(estimate_reading_minutes(""), 1);
+        assert_eq!(estimate_reading_minutes(&"word ".repeat(200)), 1);
+        assert_eq!(estimate_reading_minutes(&"word ".repeat(201)), 2);
+        assert_eq!(estimate_reading_minutes(&"word ".repeat(1000)), 5);
+    }
+
+    #[test]
+    fn test_clean_summary() {
+        assert_eq!(clean_summary("First paragraph.\n\n\n\nSecond paragraph."), "First paragraph.\n\nSecond paragraph.");
+        assert_eq!(clean_summary("Line one.   \nLine two.\t\n\n"), "Line one.\nLine two.");
+        assert_eq!(clean_summary("\n\nIntro.\r\n \r\n\r\nDetails."), "Intro.\n\nDetails.");
+        assert_eq!(clean_summary("- Point one.\n- Point two."), "- Point one.\n- Point two.");
+        assert_eq!(clean_summary("  \n\n"), "");
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::config_helper::{get_config_or, get_optional_config};
+use crate::readrss::Story;
+
+const DEFAULT_EXPORT_DIR: &str = "exports";
+const DEFAULT_RETENTION_HOURS: u64 = 24;
+
+// Long multi-story digests can go out as a Markdown file link instead of several chat messages
+pub fn is_enabled() -> bool {
+    get_config_or("export.enabled", false)
+}
+
+// There's no usable default for the public URL, so export.base_url is required once exports are on
+pub fn check_config() -> Result<(), String> {
+    if is_enabled() && base_url().is_none() {
+        return Err("export.enabled is set but export.base_url is missing".to_string());
+    }
+    Ok(())
+}
+
+fn base_url() -> Option<String> {
+    get_optional_config("export.base_url").filter(|url| !url.trim().is_empty())
+}
+
+pub fn dir() -> String {
+    get_config_or("export.dir", DEFAULT_EXPORT_DIR.to_string())
+}
+
+pub fn render_digest(stories: &[Story], summaries: &[String]) -> String {
+    let sections = stories
+        .iter()
+        .zip(summaries)
+        .enumerate()
+        .map(|(i, (story, summary))| format!("## {}. {}\n\n{}\n\n{}", i + 1, story.story, story.storylink, summary.trim()))
+        .collect::<Vec<String>>();
+    format!("# Hacker News digest\n\n{}\n", sections.join("\n\n"))
+}
+
+// Files are served by the /exports route, so export.base_url has to point at it
+pub fn save(content: &str) -> io::Result<String> {
+    let base_url = base_url().ok_or_else(|| io::Error::other("export.base_url is not set"))?;
+    let dir = dir();
+    let retention_hours = get_config_or("export.retention_hours", DEFAULT_RETENTION_HOURS);
+    if retention_hours > 0 {
+        match remove_expired(Path::new(&dir), Duration::from_secs(retention_hours * 60 * 60), SystemTime::now()) {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} expired exports", removed),
+            Err(e) => log::warn!("Failed to remove expired exports: {}", e),
+        }
+    }
+    save_to(Path::new(&dir), &base_url, content)
+}
+
+// Exported links stop working once their file is older than export.retention_hours
+fn remove_expired(dir: &Path, max_age: Duration, now: SystemTime) -> io::Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "md") {
+            continue;
+        }
+        let modified = fs::metadata(&path)?.modified()?;
+        if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn save_to(dir: &Path, base_url: &str, content: &str) -> io::Result<String> {
+    fs::create_dir_all(dir)?;
+    let file_name = format!("{}.md", Uuid::new_v4());
+    fs::write(dir.join(&file_name), content)?;
+    Ok(format!("{}/{}", base_url.trim_end_matches('/'), file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_digest() {
+        let stories = vec![
+            Story {
+                storylink: "https://example.com/rust".to_string(),
+                story: "Rust 2.0 released".to_string(),
+                published: None,
+            },
+            Story {
+                storylink: "https://example.com/sqlite".to_string(),
+                story: "A new SQLite release".to_string(),
+                published: None,
+            },
+        ];
+        let summaries = vec!["Rust 摘要。\n".to_string(), "SQLite 摘要。".to_string()];
+
+        assert_eq!(
+            render_digest(&stories, &summaries),
+            "# Hacker News digest\n\n\
+             ## 1. Rust 2.0 released\n\nhttps://example.com/rust\n\nRust 摘要。\n\n\
+             ## 2. A new SQLite release\n\nhttps://example.com/sqlite\n\nSQLite 摘要。\n"
+        );
+    }
+
+    #[test]
+    fn test_save_returns_url() {
+        let dir = std::env::temp_dir().join(format!("exports_{}", Uuid::new_v4()));
+
+        let url = save_to(&dir, "https://bot.example.com/exports/", "# Hacker News digest\n").unwrap();
+
+        let file_name = url.strip_prefix("https://bot.example.com/exports/").unwrap();
+        assert!(file_name.ends_with(".md"));
+        assert_eq!(fs::read_to_string(dir.join(file_name)).unwrap(), "# Hacker News digest\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_expired_keeps_recent_exports() {
+        let dir = std::env::temp_dir().join(format!("exports_{}", Uuid::new_v4()));
+        let now = SystemTime::now();
+        let recent = save_to(&dir, "https://bot.example.com/exports", "recent").unwrap();
+        let expired = save_to(&dir, "https://bot.example.com/exports", "expired").unwrap();
+        let file_name = |url: &str| url.rsplit('/').next().unwrap().to_string();
+        fs::File::options()
+            .write(true)
+            .open(dir.join(file_name(&expired)))
+            .unwrap()
+            .set_modified(now - Duration::from_secs(25 * 60 * 60))
+            .unwrap();
+
+        assert_eq!(remove_expired(&dir, Duration::from_secs(24 * 60 * 60), now).unwrap(), 1);
+        assert!(dir.join(file_name(&recent)).exists());
+        assert!(!dir.join(file_name(&expired)).exists());
+        assert_eq!(remove_expired(&dir.join("missing"), Duration::from_secs(60), now).unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
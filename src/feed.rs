@@ -0,0 +1,492 @@
+use std::error::Error;
+
+use atom_syndication::Feed;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use rss::Channel;
+use scraper::{Html, Selector};
+
+use crate::config_helper::{get_config_or, get_optional_config};
+use crate::metrics;
+use crate::readrss::Story;
+
+const DEFAULT_STORY_SELECTOR: &str = ".storylink a";
+const EMPTY_PARSE_LOG_CHARS: usize = 500;
+
+pub struct StorySelector {
+    // Tried in order until one finds stories
+    selectors: Vec<(String, Selector)>,
+    title_attr: Option<String>,
+    max_age: Option<Duration>,
+}
+
+impl StorySelector {
+    #[cfg(test)]
+    pub fn parse(selector: &str, title_attr: Option<String>) -> Result<Self, Box<dyn Error>> {
+        StorySelector::parse_all(&[selector.to_string()], title_attr)
+    }
+
+    pub fn parse_all(selectors: &[String], title_attr: Option<String>) -> Result<Self, Box<dyn Error>> {
+        let selectors = selectors
+            .iter()
+            .map(|selector| {
+                Selector::parse(selector)
+                    .map(|parsed| (selector.clone(), parsed))
+                    .map_err(|e| format!("Invalid story selector {:?}: {}", selector, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(StorySelector {
+            selectors,
+            title_attr: title_attr.filter(|attr| !attr.is_empty()),
+            max_age: None,
+        })
+    }
+
+    // rss.story_selectors takes over from rss.story_selector when it lists any
+    pub fn from_config() -> Result<Self, Box<dyn Error>> {
+        let max_age_hours: i64 = get_config_or("rss.max_age_hours", 0);
+        let mut selectors: Vec<String> = get_config_or("rss.story_selectors", Vec::new());
+        if selectors.is_empty() {
+            selectors.push(get_config_or("rss.story_selector", DEFAULT_STORY_SELECTOR.to_string()));
+        }
+        let selector = StorySelector::parse_all(&selectors, get_optional_config("rss.title_attr"))?;
+        Ok(selector.with_max_age((max_age_hours > 0).then(|| Duration::hours(max_age_hours))))
+    }
+
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    // Items without a date, or with one we can't parse, are kept rather than silently dropped
+    fn is_recent_rss(&self, pub_date: Option<&str>) -> bool {
+        let (Some(_), Some(pub_date)) = (self.max_age, pub_date) else {
+            return true;
+        };
+
+        match DateTime::parse_from_rfc2822(pub_date.trim()) {
+            Ok(published) => self.is_recent(published),
+            Err(e) => {
+                log::warn!("Keeping feed item with unparseable pubDate {:?}: {}", pub_date, e);
+                true
+            }
+        }
+    }
+
+    fn is_recent(&self, published: DateTime<FixedOffset>) -> bool {
+        self.max_age
+            .is_none_or(|max_age| Utc::now().signed_duration_since(published) <= max_age)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FeedFormat {
+    Rss,
+    Atom,
+}
+
+pub fn detect_format(content: &[u8]) -> Option<FeedFormat> {
+    let text = String::from_utf8_lossy(content);
+    match (text.find("<rss"), text.find("<feed")) {
+        (Some(rss), Some(atom)) if atom < rss => Some(FeedFormat::Atom),
+        (Some(_), _) => Some(FeedFormat::Rss),
+        (None, Some(_)) => Some(FeedFormat::Atom),
+        (None, None) => None,
+    }
+}
+
+// `rss.format` forces "rss", "atom" or "algolia"; anything else sniffs the document
+pub fn parse_with_format(content: &[u8], format: &str, selector: &StorySelector) -> Result<Vec<Story>, Box<dyn Error>> {
+    match format.to_lowercase().as_str() {
+        "rss" => parse_rss(content, selector),
+        "atom" => parse_atom(content, selector),
+        "algolia" => parse_algolia(content),
+        _ => parse_any(content, selector),
+    }
+}
+
+// The HN Algolia search API, e.g. ?tags=front_page; Ask HN posts have no url and link to the discussion
+fn parse_algolia(content: &[u8]) -> Result<Vec<Story>, Box<dyn Error>> {
+    let response: serde_json::Value = serde_json::from_slice(content)?;
+    let hits = response["hits"].as_array().ok_or("Algolia response has no hits")?;
+
+    Ok(hits
+        .iter()
+        .filter_map(|hit| {
+            let title = hit["title"].as_str()?;
+            let published = hit["created_at"]
+                .as_str()
+                .and_then(|created_at| DateTime::parse_from_rfc3339(created_at).ok())
+                .map(|created_at| created_at.with_timezone(&Utc));
+            let link = match hit["url"].as_str().filter(|url| !url.is_empty()) {
+                Some(url) => url.to_string(),
+                None => format!("https://news.ycombinator.com/item?id={}", hit["objectID"].as_str()?),
+            };
+            Some(Story {
+                storylink: link,
+                story: title.to_string(),
+                published,
+            })
+        })
+        .collect())
+}
+
+pub fn parse_any(content: &[u8], selector: &StorySelector) -> Result<Vec<Story>, Box<dyn Error>> {
+    match detect_format(content) {
+        Some(FeedFormat::Rss) => parse_rss(content, selector),
+        Some(FeedFormat::Atom) => parse_atom(content, selector),
+        None => Err("Unrecognized feed format".into()),
+    }
+}
+
+fn parse_rss(content: &[u8], selector: &StorySelector) -> Result<Vec<Story>, Box<dyn Error>> {
+    let channel = Channel::read_from(content)?;
+    let items: Vec<&rss::Item> = channel
+        .items()
+        .iter()
+        .filter(|item| selector.is_recent_rss(item.pub_date()))
+        .collect();
+
+    let description = items.iter().find_map(|item| item.description());
+    if let Some(stories) = first_digest(items.iter().map(|item| item.description()), selector) {
+        return Ok(stories);
+    }
+    // Digest HTML the selectors can't read usually means the upstream markup changed,
+    // even when the per-item links below still produce a list
+    if description.is_some() {
+        record_empty_parse(description);
+    }
+
+    // Plain feeds list one story per item rather than a digest in the description
+    let stories: Vec<Story> = items
+        .iter()
+        .filter_map(|item| {
+            Some(Story {
+                storylink: item.link()?.to_owned(),
+                story: item.title()?.to_owned(),
+                published: item
+                    .pub_date()
+                    .and_then(|pub_date| DateTime::parse_from_rfc2822(pub_date.trim()).ok())
+                    .map(|published| published.with_timezone(&Utc)),
+            })
+        })
+        .collect();
+    if stories.is_empty() && description.is_none() {
+        record_empty_parse(None);
+    }
+    Ok(stories)
+}
+
+fn parse_atom(content: &[u8], selector: &StorySelector) -> Result<Vec<Story>, Box<dyn Error>> {
+    let feed = Feed::read_from(content)?;
+    let entries: Vec<&atom_syndication::Entry> = feed
+        .entries()
+        .iter()
+        .filter(|entry| selector.is_recent(*entry.published().unwrap_or(entry.updated())))
+        .collect();
+
+    let html = entries.iter().find_map(|entry| entry_html(entry));
+    if let Some(stories) = first_digest(entries.iter().map(|entry| entry_html(entry)), selector) {
+        return Ok(stories);
+    }
+    if html.is_some() {
+        record_empty_parse(html);
+    }
+
+    let stories: Vec<Story> = entries
+        .iter()
+        .filter_map(|entry| {
+            Some(Story {
+                storylink: entry.links().first()?.href().to_owned(),
+                story: entry.title().as_str().to_owned(),
+                published: Some(entry.published().unwrap_or(entry.updated()).with_timezone(&Utc)),
+            })
+        })
+        .collect();
+    if stories.is_empty() && html.is_none() {
+        record_empty_parse(None);
+    }
+    Ok(stories)
+}
+
+fn entry_html(entry: &atom_syndication::Entry) -> Option<&str> {
+    entry
+        .content()
+        .and_then(|content| content.value())
+        .or_else(|| entry.summary().map(|summary| summary.as_str()))
+}
+
+// A malformed first item shouldn't hide a usable digest further down the feed
+fn first_digest<'a>(htmls: impl Iterator<Item = Option<&'a str>>, selector: &StorySelector) -> Option<Vec<Story>> {
+    htmls.enumerate().find_map(|(index, html)| {
+        let stories = stories_from_html(html?, selector);
+        if stories.is_empty() {
+            return None;
+        }
+        if index > 0 {
+            log::info!("Using the digest from feed item {}", index);
+        }
+        Some(stories)
+    })
+}
+
+// An empty parse usually means the upstream HTML changed, so keep a sample of what we got
+fn record_empty_parse(html: Option<&str>) {
+    metrics::increment(metrics::RSS_PARSE_EMPTY_TOTAL);
+    log::warn!("Story selectors found no stories in the feed");
+
+    if get_config_or("rss.log_empty_html", false) {
+        let sample: String = html.unwrap_or_default().chars().take(EMPTY_PARSE_LOG_CHARS).collect();
+        log::debug!("Feed HTML with no stories: {}", sample);
+    }
+}
+
+pub fn stories_from_html(html: &str, selector: &StorySelector) -> Vec<Story> {
+    // Parse the HTML description to get the story links and titles
+    let html = Html::parse_document(html);
+    for (position, (source, candidate)) in selector.selectors.iter().enumerate() {
+        let stories = select_stories(&html, candidate, selector.title_attr.as_deref());
+        if !stories.is_empty() {
+            if position > 0 {
+                log::info!("Story selector {:?} found {} stories", source, stories.len());
+            }
+            return stories;
+        }
+    }
+    Vec::new()
+}
+
+fn select_stories(html: &Html, selector: &Selector, title_attr: Option<&str>) -> Vec<Story> {
+    html.select(selector)
+        .filter_map(|storylink| {
+            let href = storylink.value().attr("href")?;
+            let title = match title_attr {
+                Some(attr) => storylink.value().attr(attr)?.to_owned(),
+                None => storylink.text().collect::<String>(),
+            };
+            Some(Story {
+                storylink: href.to_owned(),
+                story: title,
+                published: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Hacker News Daily</title>
+    <link>https://www.daemonology.net/hn-daily/</link>
+    <description>The best of Hacker News</description>
+    <item>
+      <title>Hacker News Daily 2024-05-01</title>
+      <link>https://www.daemonology.net/hn-daily/2024-05-01.html</link>
+      <description>&lt;ul&gt;&lt;li&gt;&lt;span class="storylink"&gt;&lt;a href="https://example.com/one"&gt;Story one&lt;/a&gt;&lt;/span&gt;&lt;/li&gt;&lt;li&gt;&lt;span class="storylink"&gt;&lt;a href="https://example.com/two"&gt;Story two&lt;/a&gt;&lt;/span&gt;&lt;/li&gt;&lt;/ul&gt;</description>
+    </item>
+  </channel>
+</rss>"#;
+
+    const SAMPLE_ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>HN Front Page</title>
+  <id>urn:uuid:60a76c80-d399-11d9-b93C-0003939e0af6</id>
+  <updated>2024-05-01T12:00:00Z</updated>
+  <entry>
+    <title>Atom story one</title>
+    <link href="https://example.com/atom-one"/>
+    <id>urn:uuid:1</id>
+    <updated>2024-05-01T12:00:00Z</updated>
+  </entry>
+  <entry>
+    <title>Atom story two</title>
+    <link href="https://example.com/atom-two"/>
+    <id>urn:uuid:2</id>
+    <updated>2024-05-01T11:00:00Z</updated>
+  </entry>
+</feed>"#;
+
+    fn default_selector() -> StorySelector {
+        StorySelector::parse(DEFAULT_STORY_SELECTOR, None).unwrap()
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(SAMPLE_RSS.as_bytes()), Some(FeedFormat::Rss));
+        assert_eq!(detect_format(SAMPLE_ATOM.as_bytes()), Some(FeedFormat::Atom));
+        assert_eq!(detect_format(b"<html></html>"), None);
+    }
+
+    #[test]
+    fn test_parse_any_rss() {
+        let stories = parse_any(SAMPLE_RSS.as_bytes(), &default_selector()).unwrap();
+
+        assert_eq!(stories.len(), 2);
+        assert_eq!(stories[0].story, "Story one");
+        assert_eq!(stories[1].storylink, "https://example.com/two");
+    }
+
+    #[test]
+    fn test_parse_with_forced_format() {
+        assert_eq!(parse_with_format(SAMPLE_ATOM.as_bytes(), "atom", &default_selector()).unwrap().len(), 2);
+        assert!(parse_with_format(SAMPLE_ATOM.as_bytes(), "rss", &default_selector()).is_err());
+    }
+
+    #[test]
+    fn test_parse_any_atom() {
+        let stories = parse_any(SAMPLE_ATOM.as_bytes(), &default_selector()).unwrap();
+
+        assert_eq!(stories.len(), 2);
+        assert_eq!(stories[0].story, "Atom story one");
+        assert_eq!(stories[1].storylink, "https://example.com/atom-two");
+    }
+
+    #[test]
+    fn test_stories_from_html_with_custom_selector() {
+        let html = r#"<table>
+            <tr><td class="title"><a class="titlelink" href="https://example.com/a" title="Story A">A</a></td></tr>
+            <tr><td class="title"><a class="titlelink" href="https://example.com/b" title="Story B">B</a></td></tr>
+            <tr><td class="subtext"><a href="https://example.com/user">user</a></td></tr>
+        </table>"#;
+
+        let selector = StorySelector::parse("td.title a.titlelink", Some("title".to_string())).unwrap();
+        let stories = stories_from_html(html, &selector);
+
+        assert_eq!(stories.len(), 2);
+        assert_eq!(stories[0].story, "Story A");
+        assert_eq!(stories[1].storylink, "https://example.com/b");
+
+        let selector = StorySelector::parse("td.title a.titlelink", None).unwrap();
+        assert_eq!(stories_from_html(html, &selector)[0].story, "A");
+    }
+
+    #[test]
+    fn test_malformed_selector_is_rejected() {
+        assert!(StorySelector::parse("a..storylink", None).is_err());
+    }
+
+    #[test]
+    fn test_empty_parse_increments_counter() {
+        let rss = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Hacker News Daily</title>
+    <item>
+      <description><![CDATA[<ul><li class="renamed"><a href="https://example.com/">Story</a></li></ul>]]></description>
+    </item>
+  </channel>
+</rss>"#;
+        let before = metrics::get(metrics::RSS_PARSE_EMPTY_TOTAL);
+
+        let stories = parse_with_format(rss.as_bytes(), "rss", &default_selector()).unwrap();
+
+        assert!(stories.is_empty());
+        assert!(metrics::get(metrics::RSS_PARSE_EMPTY_TOTAL) > before);
+    }
+
+    #[test]
+    fn test_selector_miss_is_recorded_before_link_fallback() {
+        let rss = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Hacker News Daily</title>
+    <item>
+      <title>Hacker News Daily 2024-05-01</title>
+      <link>https://www.daemonology.net/hn-daily/2024-05-01.html</link>
+      <description><![CDATA[<ul><li class="renamed"><a href="https://example.com/">Story</a></li></ul>]]></description>
+    </item>
+  </channel>
+</rss>"#;
+        let before = metrics::get(metrics::RSS_PARSE_EMPTY_TOTAL);
+
+        let stories = parse_with_format(rss.as_bytes(), "rss", &default_selector()).unwrap();
+
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].storylink, "https://www.daemonology.net/hn-daily/2024-05-01.html");
+        assert!(metrics::get(metrics::RSS_PARSE_EMPTY_TOTAL) > before);
+    }
+
+    #[test]
+    fn test_max_age_filters_old_items() {
+        let pub_date = |hours_ago: i64| (Utc::now() - Duration::hours(hours_ago)).to_rfc2822();
+        let rss = format!(
+            r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Hacker News</title>
+    <item><title>Recent</title><link>https://example.com/recent</link><pubDate>{}</pubDate></item>
+    <item><title>Old</title><link>https://example.com/old</link><pubDate>{}</pubDate></item>
+    <item><title>Undated</title><link>https://example.com/undated</link></item>
+    <item><title>Garbled</title><link>https://example.com/garbled</link><pubDate>yesterday-ish</pubDate></item>
+  </channel>
+</rss>"#,
+            pub_date(1),
+            pub_date(48)
+        );
+        let selector = default_selector().with_max_age(Some(Duration::hours(24)));
+
+        let stories = parse_with_format(rss.as_bytes(), "rss", &selector).unwrap();
+        let titles: Vec<&str> = stories.iter().map(|story| story.story.as_str()).collect();
+
+        assert_eq!(titles, vec!["Recent", "Undated", "Garbled"]);
+        assert!(stories[0].published.is_some());
+        assert!(stories[1].published.is_none());
+        assert!(stories[2].published.is_none());
+        assert_eq!(parse_with_format(rss.as_bytes(), "rss", &default_selector()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_digest_from_later_item() {
+        let rss = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Hacker News Daily</title>
+    <item>
+      <title>Broken digest</title>
+    </item>
+    <item>
+      <title>Yesterday</title>
+      <description><![CDATA[<ul><li class="storylink"><a href="https://example.com/kept">Kept story</a></li></ul>]]></description>
+    </item>
+  </channel>
+</rss>"#;
+
+        let stories = parse_with_format(rss.as_bytes(), "rss", &default_selector()).unwrap();
+
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].story, "Kept story");
+        assert_eq!(stories[0].storylink, "https://example.com/kept");
+    }
+
+    #[test]
+    fn test_parse_algolia_front_page() {
+        let response = r#"{"hits":[
+            {"title":"Rust 2.0","url":"https://example.com/rust","objectID":"1"},
+            {"title":"Ask HN: What are you working on?","url":null,"objectID":"2"},
+            {"url":"https://example.com/untitled","objectID":"3"}
+        ]}"#;
+
+        let stories = parse_with_format(response.as_bytes(), "algolia", &StorySelector::parse(".storylink a", None).unwrap()).unwrap();
+
+        assert_eq!(stories.len(), 2);
+        assert_eq!(stories[0].storylink, "https://example.com/rust");
+        assert_eq!(stories[1].storylink, "https://news.ycombinator.com/item?id=2");
+        assert_eq!(stories[1].story, "Ask HN: What are you working on?");
+    }
+
+    #[test]
+    fn test_falls_through_to_next_selector() {
+        let html = r#"<ul><li><span class="title"><a href="https://example.com/one">Story one</a></span></li></ul>"#;
+        let selector = StorySelector::parse_all(&["a.storylink".to_string(), ".title a".to_string()], None).unwrap();
+
+        let stories = stories_from_html(html, &selector);
+
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].storylink, "https://example.com/one");
+        assert_eq!(stories[0].story, "Story one");
+        assert!(StorySelector::parse_all(&[".title a".to_string(), "a[".to_string()], None).is_err());
+    }
+}
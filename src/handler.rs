@@ -1,66 +1,182 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
 use bytes::Bytes;
+use futures::stream::{FuturesOrdered, StreamExt};
+use once_cell::sync::Lazy;
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use warp::{
     http::{Response, StatusCode},
-    Rejection, Reply,
+    Filter, Rejection, Reply,
 };
 use warp::hyper::Body;
 
-use crate::{chatgpt, config_helper, kagi, line_helper, readrss, request_handler};
-use crate::config_helper::{get_config, get_secret};
+use crate::{budget, chatgpt, commands, config_helper, content, export, integrations, line_helper, media, metrics, quota, rate_limit, readrss, redact, request_handler, request_id, schedule, stories, summary};
+use crate::chatgpt::ConversationResult;
+use crate::clock::{Clock, SystemClock};
+use crate::commands::Command;
+use crate::rate_limit::RateDecision;
+use crate::request_handler::SendId;
+use crate::config_helper::{get_config, get_config_or, get_optional_config, get_secret};
 use crate::line_helper::{
-    LineBroadcastRequest, LineMessage, LineMessageRequest, LineSendMessageRequest,
+    LineBroadcastRequest, LineMessage, LineSendMessageRequest,
 };
+use crate::readrss::Story;
+use crate::stories::StoryIndex;
+use crate::webhook_queue::WebhookQueue;
+
+const MAX_SUMMARY_LANGUAGES: usize = 3;
+const DEFAULT_MAX_INDEXES: usize = 5;
+const NO_STORIES_ERROR: &str = "No stories found in RSS feed";
+const DEFAULT_WEBHOOK_WORKERS: usize = 4;
+const DEFAULT_WEBHOOK_QUEUE_CAPACITY: usize = 100;
+const READING_TIME_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_WARMUP_LEAD_MINUTES: i64 = 15;
+const DEFAULT_PUSH_INTERVAL_MS: u64 = 500;
+const DEFAULT_SUMMARY_MAX_INPUT_TOKENS: usize = 6000;
+const MAX_CACHED_TITLE_TRANSLATIONS: usize = 1000;
+
+// Events keep the request ID of the webhook call that delivered them
+static WEBHOOK_QUEUE: Lazy<WebhookQueue<(String, Bytes)>> = Lazy::new(|| {
+    WebhookQueue::start(
+        get_config_or("webhook.workers", DEFAULT_WEBHOOK_WORKERS),
+        get_config_or("webhook.queue_capacity", DEFAULT_WEBHOOK_QUEUE_CAPACITY),
+        |(request_id, body): (String, Bytes)| request_id::scope(request_id, process_request(body)),
+    )
+});
 
-pub async fn conversation_handler(content: Bytes) -> Result<impl Reply, Rejection> {
-    let conversions = String::from_utf8(content.to_vec()).unwrap();
-    let res = chatgpt::run_conversation(conversions).await;
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
 
-    let function_call: Value = serde_json::from_str(res.as_ref().unwrap().as_str()).unwrap();
+// Keyed by title and language; the same stories are listed many times a day
+static TITLE_TRANSLATIONS: Lazy<std::sync::Mutex<HashMap<(String, String), String>>> =
+    Lazy::new(|| std::sync::Mutex::new(HashMap::new()));
+
+pub fn mark_started() {
+    Lazy::force(&STARTED_AT);
+}
 
-    log::info!("function_call: {}", function_call);
+pub async fn hello_handler() -> Result<impl Reply, Rejection> {
+    let debug_endpoints = get_config_or("server.debug_endpoints", false);
+    Ok(warp::reply::json(&hello_response(debug_endpoints, STARTED_AT.elapsed())))
+}
 
-    match function_call.get("name").and_then(Value::as_str) {
-        Some(function_name) => {
-            let arguments_value = function_call["arguments"].as_str().unwrap();
-            let arguments: Value = serde_json::from_str(arguments_value).unwrap();
+// Checked after the path matches, so a disabled route falls through to warp's 404
+pub fn route_enabled(name: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    route_gate(move || get_config_or(&format!("routes.{}", name), true))
+}
 
-            log::info!("arguments: {}", arguments);
+// /exports only serves files while export.enabled is on
+pub fn exports_enabled() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    route_gate(export::is_enabled)
+}
 
-            if function_name == "push_summary" {
-                let index = arguments["indexes"].as_array().unwrap();
-                log::info!("index: {:?}", index); // Convert Vec<usize> to string representation
+fn route_gate<F>(is_enabled: F) -> impl Filter<Extract = (), Error = Rejection> + Clone
+where
+    F: Fn() -> bool + Clone + Send + Sync + 'static,
+{
+    warp::any()
+        .and_then(move || {
+            let enabled = is_enabled();
+            async move {
+                match enabled {
+                    true => Ok(()),
+                    false => Err(warp::reject::not_found()),
+                }
             }
+        })
+        .untuple_one()
+}
 
-            let response = warp::reply::json(&json!(function_call));
-            Ok(warp::reply::with_status(response, StatusCode::OK))
-        }
-        None => {
-            let response = warp::reply::json(&json!({
-                "message": function_call["message"].as_str().unwrap(),
-            }));
-            Ok(warp::reply::with_status(response, StatusCode::OK))
+pub async fn metrics_handler() -> Result<impl Reply, Rejection> {
+    quota::refresh_metrics().await;
+    Ok(metrics::render())
+}
+
+fn hello_response(debug_endpoints: bool, uptime: Duration) -> Value {
+    if !debug_endpoints {
+        return json!({"success": true});
+    }
+
+    json!({
+        "success": true,
+        "uptime_secs": uptime.as_secs(),
+        "version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestPromptRequest {
+    prompt_key: String,
+    content: String,
+}
+
+pub async fn test_prompt_handler(request: TestPromptRequest) -> Result<impl Reply, Rejection> {
+    if !get_config_or("server.debug_endpoints", false) {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(run_test_prompt(request).await)
+}
+
+async fn run_test_prompt(request: TestPromptRequest) -> Response<Body> {
+    if config_helper::get_optional_prompt(&request.prompt_key).is_none() {
+        let error_msg = json!({"success": false, "error": format!("Unknown prompt key: {}", request.prompt_key)});
+        return warp::reply::with_status(warp::reply::json(&error_msg), StatusCode::NOT_FOUND).into_response();
+    }
+
+    let result = chatgpt::get_chatgpt_response(&request.prompt_key, request.content, 0.05, "chatgpt.model")
+        .await
+        .map_err(|e| e.to_string());
+    match result {
+        Ok(output) => warp::reply::json(&json!({"success": true, "output": output})).into_response(),
+        Err(e) => {
+            log::error!("Prompt test failed: {}", e);
+            handle_error_response("Error running prompt").await
         }
     }
 }
 
+pub async fn conversation_handler(request_id: String, content: Bytes) -> Result<impl Reply, Rejection> {
+    request_id::scope(request_id, conversation(content)).await
+}
+
+async fn conversation(content: Bytes) -> Result<Response<Body>, Rejection> {
+    let conversation = String::from_utf8(content.to_vec()).unwrap();
+    if let Some(message) = budget::guard_message() {
+        let error_msg = json!({"success": false, "error": message});
+        return Ok(warp::reply::with_status(warp::reply::json(&error_msg), StatusCode::TOO_MANY_REQUESTS).into_response());
+    }
+    if chatgpt::is_flagged(&conversation).await {
+        let error_msg = json!({"success": false, "error": "Content flagged by moderation"});
+        return Ok(warp::reply::with_status(warp::reply::json(&error_msg), StatusCode::UNPROCESSABLE_ENTITY).into_response());
+    }
+
+    let result = match chatgpt::run_conversation(conversation, &[]).await.map_err(|e| e.to_string()) {
+        Ok(result) => result,
+        Err(e) => {
+            log::error!("Conversation failed: {}", e);
+            return Ok(handle_error_response("Conversation failed").await);
+        }
+    };
+
+    Ok(warp::reply::json(&result.to_json()).into_response())
+}
+
 pub async fn parse_request_handler(
     x_line_signature: String,
+    request_id: String,
     body: Bytes,
 ) -> Result<impl Reply, Rejection> {
     let validation_result = validate_signature(x_line_signature, &body).await;
 
-    // Clone or copy necessary data for the new task
-    let body_clone = body.clone();
-
-    // Process the other logic asynchronously
-    tokio::spawn(async move {
-        process_request(body_clone).await;
-    });
-
     match validation_result {
         Ok(()) => {
-            // Immediately return HTTP 200 OK after signature validation
+            // Process the event on the worker pool and return HTTP 200 OK right away
+            WEBHOOK_QUEUE.enqueue((request_id, body));
             Ok(warp::reply::with_status(
                 warp::reply::json(&json!({"success": true})),
                 StatusCode::OK,
@@ -104,20 +220,63 @@ async fn process_request(body: Bytes) {
         .unwrap_or_default()
         .to_string();
 
-    let language_code = chatgpt::get_language_code(text.to_owned()).await.unwrap();
-
     let reply_token = json_value["events"][0]["replyToken"].as_str();
 
     let user_id = json_value["events"][0]["source"]["userId"].as_str();
 
-    let res = chatgpt::run_conversation(text).await.unwrap();
+    let source_type = json_value["events"][0]["source"]["type"].as_str();
+    if !is_allowed_source(source_type, &get_config_or("webhook.allowed_source_types", Vec::new())) {
+        log::info!("Skipping event from source type {:?}", source_type.unwrap_or_default());
+        return;
+    }
+
+    if let Some(user_id) = user_id {
+        match rate_limit::check(user_id) {
+            RateDecision::Allowed => {}
+            RateDecision::Throttled => {
+                log::warn!("Rate limit exceeded for {}", redact::redact(user_id));
+                reply_rate_limited(&channel_token, reply_token, user_id).await;
+                return;
+            }
+            RateDecision::Suppressed => return,
+        }
+
+        if json_value["events"][0]["type"] == "message" {
+            request_handler::show_loading(&channel_token, user_id).await;
+        }
+    }
+
+    if let Some(command) = commands::parse(&text) {
+        log::info!("command: {:?}", command);
+        command_handler(command, &channel_token, reply_token, user_id).await;
+        return;
+    }
+
+    if let Some(message) = budget::guard_message() {
+        reply_budget_reached(&channel_token, reply_token, user_id, message).await;
+        return;
+    }
+
+    if chatgpt::is_flagged(&text).await {
+        reply_moderation_refused(&channel_token, reply_token, user_id).await;
+        return;
+    }
 
-    let function_call: Value = serde_json::from_str(res.as_str()).unwrap();
+    let detected = chatgpt::get_language_code(text.to_owned()).await.map_err(|e| e.to_string());
+    let language_code = language_or_default(detected, default_language());
 
-    log::info!("function_call: {}", function_call);
+    // Only a list the user was actually shown is passed on, push_summary resolves indexes against the same snapshot
+    let shown_stories = user_id.and_then(stories::snapshot_for).unwrap_or_default();
+    let result = match chatgpt::run_conversation(text, &shown_stories).await.map_err(|e| e.to_string()) {
+        Ok(result) => result,
+        Err(e) => {
+            log::error!("Conversation failed: {}", e);
+            return;
+        }
+    };
 
     function_call_handler(
-        function_call,
+        result,
         channel_token,
         reply_token,
         user_id,
@@ -126,33 +285,135 @@ async fn process_request(body: Bytes) {
         .await;
 }
 
+// An empty webhook.allowed_source_types lets users, groups and rooms all through
+fn is_allowed_source(source_type: Option<&str>, allowed: &[String]) -> bool {
+    allowed.is_empty() || source_type.is_some_and(|source_type| allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(source_type)))
+}
+
+async fn reply_rate_limited(channel_token: &str, reply_token: Option<&str>, user_id: &str) {
+    let Some(reply_token) = reply_token else {
+        return;
+    };
+
+    let message = convert_to_line_message(config_helper::get_prompt("prompt.rate_limited")).await;
+    if request_handler::handle_reply_request(channel_token, reply_token, Some(user_id), vec![message])
+        .await
+        .is_err()
+    {
+        handle_error_response("Error reply rate limited").await;
+    }
+}
+
+async fn reply_moderation_refused(channel_token: &str, reply_token: Option<&str>, user_id: Option<&str>) {
+    let Some(reply_token) = reply_token else {
+        return;
+    };
+
+    let message = convert_to_line_message(config_helper::get_prompt("prompt.moderation_refused")).await;
+    if request_handler::handle_reply_request(channel_token, reply_token, user_id, vec![message])
+        .await
+        .is_err()
+    {
+        handle_error_response("Error reply moderation refused").await;
+    }
+}
+
+async fn reply_budget_reached(channel_token: &str, reply_token: Option<&str>, user_id: Option<&str>, message: String) {
+    let Some(reply_token) = reply_token else {
+        return;
+    };
+
+    let message = convert_to_line_message(message).await;
+    if request_handler::handle_reply_request(channel_token, reply_token, user_id, vec![message])
+        .await
+        .is_err()
+    {
+        handle_error_response("Error reply budget reached").await;
+    }
+}
+
+async fn command_handler(
+    command: Command,
+    channel_token: &str,
+    reply_token: Option<&str>,
+    user_id: Option<&str>,
+) {
+    match command {
+        Command::LatestStories => {
+            handle_reply_latest_story(channel_token, reply_token.unwrap(), user_id).await;
+        }
+        Command::DailySummary => {
+            if reply_daily_summary(channel_token, reply_token.unwrap(), user_id).await.is_err() {
+                handle_error_response("Error reply daily summary").await;
+            }
+        }
+        Command::Help => {
+            let message = line_helper::create_help_message(
+                &config_helper::get_prompt("prompt.help"),
+                &commands::help_capabilities(),
+                line_helper::get_configured_sender(),
+            );
+            if request_handler::handle_reply_request(channel_token, reply_token.unwrap(), user_id, vec![message])
+                .await
+                .is_err()
+            {
+                handle_error_response("Error reply help").await;
+            }
+        }
+    }
+}
+
 async fn function_call_handler(
-    function_call: Value,
+    result: ConversationResult,
     channel_token: String,
     reply_token: Option<&str>,
     user_id: Option<&str>,
     language_code: String,
 ) {
-    let function_name = function_call.get("name").and_then(Value::as_str);
+    let (name, arguments, message) = match result {
+        ConversationResult::Tool { name, arguments, message } => (name, arguments, message),
+        ConversationResult::Message(message) => {
+            handle_push_messages(&channel_token, user_id.unwrap(), message).await;
+            return;
+        }
+    };
 
-    match function_name {
-        Some("reply_latest_story") => {
-            handle_reply_latest_story(&channel_token, &reply_token.unwrap().to_string()).await;
+    if let Some(message) = &message {
+        handle_push_messages(&channel_token, user_id.unwrap(), message.clone()).await;
+    }
+
+    match name.as_str() {
+        "reply_latest_story" => {
+            handle_reply_latest_story(&channel_token, reply_token.unwrap(), user_id).await;
         }
-        Some("push_summary") => {
-            handle_push_summary(&channel_token, &user_id.unwrap(), language_code, &function_call).await;
+        "push_summary" => {
+            handle_push_summary(&channel_token, user_id.unwrap(), language_code, &arguments).await;
         }
-        Some("push_url_summary") => {
-            handle_push_url_summary(&channel_token, &user_id.unwrap(), "zh-tw".to_string(), &function_call).await;
+        "push_url_summary" => {
+            handle_push_url_summary(&channel_token, user_id.unwrap(), default_language(), &arguments).await;
         }
-        _ => {
-            handle_push_messages(&channel_token, &user_id.unwrap(), &function_call).await;
+        name => {
+            log::warn!("Unknown function call: {}", name);
+            metrics::increment(metrics::UNKNOWN_FUNCTION_CALL_TOTAL);
+            if let Some(notice) = unknown_function_notice(&message) {
+                if push_messages(&channel_token, user_id.unwrap(), vec![notice]).await.is_err() {
+                    handle_error_response("Error push messages").await;
+                }
+            }
         }
     }
 }
 
-async fn handle_reply_latest_story(channel_token: &str, reply_token: &str) {
-    match reply_latest_story(channel_token, reply_token).await {
+// An unknown tool rarely comes with message content, so tell the user instead of sending nothing
+fn unknown_function_notice(message: &Option<String>) -> Option<String> {
+    match message {
+        Some(_) => None,
+        None => Some(config_helper::get_prompt("prompt.unknown_function")),
+    }
+}
+
+async fn handle_reply_latest_story(channel_token: &str, reply_token: &str, user_id: Option<&str>) {
+    match reply_latest_story(channel_token, reply_token, user_id).await {
         Ok(_) => {},
         Err(_e) => {
             handle_error_response("Error reply latest story").await;
@@ -160,17 +421,22 @@ async fn handle_reply_latest_story(channel_token: &str, reply_token: &str) {
     }
 }
 
-async fn handle_push_summary(channel_token: &str, user_id: &str, language_code: String, function_call: &Value) {
-    let arguments: Value = serde_json::from_str(function_call["arguments"].as_str().unwrap()).unwrap();
-    let indexes = arguments
-        .get("indexes")
-        .and_then(Value::as_array)
-        .unwrap()
-        .iter()
-        .map(|i| i.as_u64().unwrap() as usize)
-        .collect::<Vec<usize>>();
+async fn handle_push_summary(channel_token: &str, user_id: &str, language_code: String, arguments: &str) {
+    let arguments = parse_push_summary_arguments(arguments);
 
-    match push_summary(channel_token, user_id, language_code, indexes).await {
+    let max_indexes = get_config_or("summary.max_indexes", DEFAULT_MAX_INDEXES);
+    let (indexes, trimmed) = limit_indexes(arguments.indexes, max_indexes);
+    if trimmed {
+        let notice = config_helper::get_prompt("prompt.summary_trimmed").replace("{max}", &max_indexes.to_string());
+        if push_messages(channel_token, user_id, vec![notice]).await.is_err() {
+            handle_error_response("Error push messages").await;
+        }
+    }
+
+    let indexes = indexes.into_iter().filter_map(StoryIndex::new).collect();
+    let include_original = resolve_include_original(arguments.include_original);
+    let language_code = supported_language(&language_code);
+    match push_summary(channel_token, user_id, language_code, indexes, arguments.translate, include_original).await {
         Ok(_) => {},
         Err(_e) => {
             handle_error_response("Error push summary").await;
@@ -178,12 +444,112 @@ async fn handle_push_summary(channel_token: &str, user_id: &str, language_code:
     }
 }
 
-async fn handle_push_messages(channel_token: &str, user_id: &str, function_call: &Value) {
-    match push_messages(
-        channel_token,
-        user_id,
-        vec![function_call["message"].as_str().unwrap().to_string()],
-    ).await {
+#[derive(Debug, Deserialize)]
+struct PushSummaryArguments {
+    indexes: Vec<usize>,
+    #[serde(default = "default_translate")]
+    translate: bool,
+    #[serde(default)]
+    include_original: Option<bool>,
+}
+
+fn default_translate() -> bool {
+    true
+}
+
+fn parse_push_summary_arguments(arguments: &str) -> PushSummaryArguments {
+    serde_json::from_str(arguments).unwrap()
+}
+
+// The tool description asks the model to cap the array, but each index costs a Kagi and a ChatGPT call
+fn limit_indexes(indexes: Vec<usize>, max_indexes: usize) -> (Vec<usize>, bool) {
+    let mut unique_indexes: Vec<usize> = Vec::new();
+    for index in indexes {
+        if !unique_indexes.contains(&index) {
+            unique_indexes.push(index);
+        }
+    }
+
+    let trimmed = unique_indexes.len() > max_indexes;
+    unique_indexes.truncate(max_indexes);
+    (unique_indexes, trimmed)
+}
+
+#[derive(Debug, Deserialize)]
+struct PushUrlSummaryArguments {
+    url: String,
+    #[serde(default)]
+    languages: Vec<String>,
+    #[serde(default)]
+    include_original: Option<bool>,
+}
+
+fn parse_push_url_summary_arguments(arguments: &str) -> PushUrlSummaryArguments {
+    serde_json::from_str(arguments).unwrap()
+}
+
+fn default_language() -> String {
+    get_config_or("i18n.default_language", "zh-tw".to_string())
+}
+
+fn language_or_default(detected: Result<String, String>, default_language: String) -> String {
+    match detected {
+        Ok(language_code) if !language_code.trim().is_empty() => language_code.trim().to_string(),
+        Ok(_) => default_language,
+        Err(e) => {
+            log::warn!("Language detection failed, using {}: {}", default_language, e);
+            default_language
+        }
+    }
+}
+
+fn supported_language(language_code: &str) -> String {
+    let supported: Vec<String> = get_config_or("i18n.supported_languages", Vec::new());
+    resolve_output_language(language_code, &supported, &default_language())
+}
+
+// Translating into a language outside the allowlist tends to go badly, so use the default instead
+fn resolve_output_language(language_code: &str, supported: &[String], default_language: &str) -> String {
+    let language = language_code.trim().to_lowercase();
+    if supported.is_empty() {
+        return language;
+    }
+
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    let is_supported = supported
+        .iter()
+        .map(|supported| supported.to_lowercase())
+        .any(|supported| supported == language || supported == primary);
+    if is_supported {
+        return language;
+    }
+
+    log::warn!("Unsupported language {:?}, using {}", language_code, default_language);
+    default_language.to_string()
+}
+
+fn resolve_include_original(requested: Option<bool>) -> bool {
+    requested.unwrap_or_else(|| get_config_or("summary.include_original", false))
+}
+
+fn resolve_summary_languages(requested: Vec<String>, default_language: String) -> Vec<String> {
+    let mut languages: Vec<String> = Vec::new();
+    for language in requested {
+        let language = language.trim().to_lowercase();
+        if !language.is_empty() && !languages.contains(&language) {
+            languages.push(language);
+        }
+    }
+    languages.truncate(MAX_SUMMARY_LANGUAGES);
+
+    if languages.is_empty() {
+        languages.push(default_language);
+    }
+    languages
+}
+
+async fn handle_push_messages(channel_token: &str, user_id: &str, message: String) {
+    match push_messages(channel_token, user_id, vec![message]).await {
         Ok(_) => {},
         Err(_e) => {
             handle_error_response("Error push messages").await;
@@ -191,11 +557,12 @@ async fn handle_push_messages(channel_token: &str, user_id: &str, function_call:
     }
 }
 
-async fn handle_push_url_summary(channel_token: &str, user_id: &str, language_code: String, function_call: &Value) {
-    let arguments = function_call.get("arguments").unwrap().as_str().unwrap();
-    let arguments_json: Value = serde_json::from_str(arguments).unwrap();
-    let url = arguments_json.get("url").unwrap().as_str().unwrap().to_string();
-    match push_url_summary(channel_token, user_id, language_code, url).await {
+async fn handle_push_url_summary(channel_token: &str, user_id: &str, language_code: String, arguments: &str) {
+    let arguments = parse_push_url_summary_arguments(arguments);
+    let requested: Vec<String> = arguments.languages.iter().map(|language| supported_language(language)).collect();
+    let languages = resolve_summary_languages(requested, supported_language(&language_code));
+    let include_original = resolve_include_original(arguments.include_original);
+    match push_url_summary(channel_token, user_id, languages, arguments.url, include_original).await {
         Ok(_) => {},
         Err(_e) => {
             handle_error_response("Error push url summary").await;
@@ -203,6 +570,21 @@ async fn handle_push_url_summary(channel_token: &str, user_id: &str, language_co
     }
 }
 
+fn quota_skipped_response(route: &str) -> Response<Body> {
+    log::warn!("Skipping {} because the LINE monthly quota is nearly exhausted", route);
+    warp::reply::with_status(
+        warp::reply::json(&json!({"success": false, "error": "LINE monthly quota nearly exhausted"})),
+        StatusCode::TOO_MANY_REQUESTS,
+    )
+    .into_response()
+}
+
+// Not an error: cron keeps calling every day and the excluded days are answered here
+fn off_day_response(route: &str) -> Response<Body> {
+    log::info!("Skipping {} because today is not in schedule.days", route);
+    warp::reply::json(&json!({"success": true, "skipped": "not a scheduled day"})).into_response()
+}
+
 async fn handle_error_response(error: &str) -> Response<Body> {
     let error_msg = json!({"success": false, "error": error});
     warp::reply::with_status(
@@ -213,7 +595,8 @@ async fn handle_error_response(error: &str) -> Response<Body> {
 
 pub async fn get_latest_stories() -> Result<impl Reply, Rejection> {
     let stories = readrss::get_last_hn_stories().await;
-    Ok(warp::reply::json(&stories))
+    let view = stories::to_latest_stories_view(&stories, get_config("rss.feed_url"), Utc::now());
+    Ok(warp::reply::json(&view))
 }
 
 pub async fn get_latest_title() -> Result<impl Reply, Rejection> {
@@ -247,128 +630,776 @@ fn reply_error_msg(error: &'static str, status: StatusCode) -> Response<Bytes> {
 }
 
 pub async fn send_line_broadcast() -> Result<impl Reply, Rejection> {
-    let token = &get_secret("channel.token");
-    let message = convert_stories_to_message().await;
+    if !schedule::should_run_today() {
+        return Ok(off_day_response("sendTodayStories"));
+    }
+    if quota::should_skip_optional_sends().await {
+        return Ok(quota_skipped_response("sendTodayStories"));
+    }
+
+    let token = get_secret("channel.token");
+    let stories = readrss::get_last_hn_stories().await;
 
-    let request_body = LineBroadcastRequest {
-        messages: vec![message],
+    let messages = if stories.is_empty() {
+        match empty_stories_notice(get_config_or("broadcast.send_empty_notice", false)).await {
+            Some(notice) => vec![notice],
+            None => return Ok(handle_error_response(NO_STORIES_ERROR).await),
+        }
+    } else {
+        stories::save_broadcast_snapshot(&stories);
+        let messages = convert_stories_to_messages(&stories).await;
+        with_pinned_stories(messages, &get_config_or("broadcast.pinned", Vec::new())).await
     };
 
-    let url = get_config("message.broadcast_url");
+    Ok(send_broadcast(&token, messages, stories.len()).await)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PinnedStory {
+    title: String,
+    url: String,
+}
 
-    let json_body = serde_json::to_string(&request_body).unwrap();
+// Pinned links follow the HN stories in their own message, marked with 📌 instead of a rank
+async fn with_pinned_stories(mut messages: Vec<LineMessage>, pinned: &[PinnedStory]) -> Vec<LineMessage> {
+    if pinned.is_empty() {
+        return messages;
+    }
+    if messages.len() >= line_helper::MAX_MESSAGES_PER_REQUEST {
+        log::warn!("No room left in the broadcast for {} pinned stories", pinned.len());
+        return messages;
+    }
+
+    let text = pinned
+        .iter()
+        .map(|pinned| format!("📌 {} ({})", pinned.title, pinned.url))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    messages.push(convert_to_line_message(text).await);
+    messages
+}
 
-    request_handler::handle_send_request(token, json_body, url.as_str()).await
+#[derive(Debug, Serialize)]
+struct BroadcastResult {
+    success: bool,
+    story_count: usize,
+    message_count: usize,
+    message_type: String,
+    broadcast_at: DateTime<Utc>,
 }
 
-pub async fn broadcast_daily_summary() -> Result<impl Reply, Rejection> {
-    let token = get_secret("channel.token");
+impl BroadcastResult {
+    fn new(messages: &[LineMessage], story_count: usize, broadcast_at: DateTime<Utc>) -> Self {
+        BroadcastResult {
+            success: true,
+            story_count,
+            message_count: messages.len(),
+            message_type: messages.first().map(|message| message.message_type.clone()).unwrap_or_default(),
+            broadcast_at,
+        }
+    }
+}
 
+// Echo what went out so cron logs show how many stories were broadcast
+async fn send_broadcast(token: &str, messages: Vec<LineMessage>, story_count: usize) -> Response<Body> {
     let url = get_config("message.broadcast_url");
+    let result = BroadcastResult::new(&messages, story_count, Utc::now());
+
+    let json_body = serde_json::to_string(&LineBroadcastRequest { messages }).unwrap();
+
+    let send_id = SendId::for_body(&url, &json_body);
+    match request_handler::send_billed_request(token, json_body, url.as_str(), &send_id).await {
+        Ok(response) if response.status().is_success() => warp::reply::json(&result).into_response(),
+        Ok(response) => {
+            let status = response.status();
+            let error_body = response.text().await.unwrap_or_default();
+            log::error!("LINE broadcast returned {}: {}", status, redact::redact(&error_body));
+            handle_error_response("LINE broadcast failed").await
+        }
+        Err(e) => {
+            log::error!("LINE broadcast failed: {}", e);
+            handle_error_response("LINE broadcast failed").await
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SummaryQuery {
+    pub lang: Option<String>,
+}
 
-    let message = get_chatgpt_summary().await;
+pub fn summary_query() -> impl Filter<Extract = (SummaryQuery,), Error = Rejection> + Clone {
+    warp::query::<SummaryQuery>()
+}
 
-    let request_body = LineBroadcastRequest {
-        messages: vec![message],
-    };
+pub async fn preview_daily_summary(query: SummaryQuery) -> Result<impl Reply, Rejection> {
+    let stories = readrss::get_last_hn_stories().await;
+    if stories.is_empty() {
+        return Ok(handle_error_response(NO_STORIES_ERROR).await);
+    }
+
+    let summary = build_daily_summary(&stories, query.lang).await;
 
-    let json_body = serde_json::to_string(&request_body).unwrap();
+    Ok(warp::reply::json(&json!({"success": true, "summary": summary})).into_response())
+}
 
-    request_handler::handle_send_request(token.as_str(), json_body, url.as_str()).await
+#[derive(Debug, Deserialize)]
+pub struct StorySummaryQuery {
+    pub index: usize,
+    pub lang: Option<String>,
 }
 
-async fn reply_latest_story(token: &str, reply_token: &str) -> Result<impl Reply, Rejection> {
-    let message = convert_stories_to_message().await;
+pub fn story_summary_query() -> impl Filter<Extract = (StorySummaryQuery,), Error = Rejection> + Clone {
+    warp::query::<StorySummaryQuery>()
+}
 
-    let request_body = LineMessageRequest {
-        replyToken: reply_token.to_string(),
-        messages: vec![message],
+// Summarizes one story from the current feed without sending anything to LINE
+pub async fn preview_story_summary(query: StorySummaryQuery) -> Result<impl Reply, Rejection> {
+    let stories = readrss::get_last_hn_stories().await;
+    let Some(story) = story_at(&stories, query.index) else {
+        return Ok(story_index_error(stories.len()));
     };
 
-    let json_body = serde_json::to_string(&request_body).unwrap();
+    let story_summary = summary::summarize_url(&story.storylink).await.text;
+    let summary = match query.lang {
+        Some(language_code) => localize_summary(story_summary, language_code, true).await,
+        None => story_summary,
+    };
 
-    let url = config_helper::get_config("message.reply_url");
+    Ok(warp::reply::json(&json!({
+        "success": true,
+        "index": query.index,
+        "title": story.story,
+        "url": story.storylink,
+        "summary": summary,
+    }))
+    .into_response())
+}
 
-    request_handler::handle_send_request(token, json_body, url.as_str()).await
+fn story_at(stories: &[Story], index: usize) -> Option<&Story> {
+    StoryIndex::new(index)?.pick(stories)
 }
 
-async fn push_summary(
-    token: &str,
-    user_id: &str,
-    language_code: String,
-    indexes: Vec<usize>,
-) -> Result<impl Reply, Rejection> {
+fn story_index_error(story_count: usize) -> Response<Body> {
+    let error_msg = json!({"success": false, "error": format!("Story index must be between 1 and {}", story_count)});
+    warp::reply::with_status(warp::reply::json(&error_msg), StatusCode::BAD_REQUEST).into_response()
+}
+
+pub async fn broadcast_daily_summary(query: SummaryQuery) -> Result<impl Reply, Rejection> {
+    if !schedule::should_run_today() {
+        return Ok(off_day_response("broadcastDailySummary"));
+    }
+    let token = get_secret("channel.token");
+
     let stories = readrss::get_last_hn_stories().await;
 
-    let mut messages = Vec::new();
+    let (messages, summary_text) = if stories.is_empty() {
+        match empty_stories_notice(get_config_or("broadcast.send_empty_notice", false)).await {
+            Some(notice) => (vec![notice], None),
+            None => return Ok(handle_error_response(NO_STORIES_ERROR).await),
+        }
+    } else {
+        let summary = get_chatgpt_summary(&stories, query.lang).await;
+        (daily_summary_messages(summary.clone(), line_helper::MAX_MESSAGES_PER_REQUEST).await, Some(summary))
+    };
 
-    for index in indexes {
-        let story = &stories[index - 1];
-        let story_summary = kagi::get_kagi_summary(story.storylink.to_owned()).await;
-        let summary_zhtw = chatgpt::translate(story_summary, language_code.to_owned())
-            .await
-            .unwrap();
-        messages.push(summary_zhtw);
+    let response = send_broadcast(&token, messages, stories.len()).await;
+    if let Some(summary_text) = summary_text {
+        integrations::mirror_daily_summary(&summary_text).await;
     }
+    Ok(response)
+}
 
-    let result = push_messages(token, user_id, messages).await;
-    result
+#[derive(Debug, Deserialize)]
+pub struct NarrowcastRequest {
+    recipient: Option<Value>,
+    filter: Option<Value>,
+    lang: Option<String>,
 }
 
-async fn push_url_summary(
-    token: &str,
-    user_id: &str,
-    language_code: String,
-    url: String,
-) -> Result<impl Reply, Rejection> {
+// The daily summary for an audience or demographic filter; LINE sends it in the background,
+// so the reply carries the request ID to check with /narrowcastProgress
+pub async fn narrowcast_daily_summary(request: NarrowcastRequest) -> Result<impl Reply, Rejection> {
+    if request.recipient.is_none() && request.filter.is_none() {
+        let error_msg = json!({"success": false, "error": "recipient or filter is required, use /broadcastDailySummary to send to everyone"});
+        return Ok(warp::reply::with_status(warp::reply::json(&error_msg), StatusCode::BAD_REQUEST).into_response());
+    }
+    if quota::should_skip_optional_sends().await {
+        return Ok(quota_skipped_response("narrowcastDailySummary"));
+    }
 
-    let story_summary = kagi::get_kagi_summary(url.to_owned()).await;
-    let summary_zhtw = chatgpt::translate(story_summary, language_code.to_owned())
-            .await
-            .unwrap();
-    let messages = vec![summary_zhtw];
+    let stories = readrss::get_last_hn_stories().await;
+    if stories.is_empty() {
+        return Ok(handle_error_response(NO_STORIES_ERROR).await);
+    }
 
-    let result = push_messages(token, user_id, messages).await;
-    result
+    let summary = get_chatgpt_summary(&stories, request.lang).await;
+    let messages = daily_summary_messages(summary, line_helper::MAX_MESSAGES_PER_REQUEST).await;
+    let token = get_secret("channel.token");
+    let result = request_handler::narrowcast_message(&token, request.recipient, request.filter, messages)
+        .await
+        .map_err(|e| e.to_string());
+    match result {
+        Ok(request_id) => Ok(warp::reply::json(&json!({"success": true, "request_id": request_id})).into_response()),
+        Err(e) => {
+            log::error!("Narrowcast failed: {}", e);
+            Ok(handle_error_response("Error narrowcast daily summary").await)
+        }
+    }
 }
 
-async fn push_messages(
-    token: &str,
-    user_id: &str,
-    text: Vec<String>,
-) -> Result<impl Reply + Sized + Sized, Rejection> {
-    let messages: Vec<LineMessage> = text
-        .iter()
-        .map(|t| LineMessage {
-            message_type: "text".to_string(),
-            text: t.to_string(),
-        })
-        .collect();
+#[derive(Debug, Deserialize)]
+pub struct NarrowcastProgressQuery {
+    request_id: String,
+}
 
-    let request = LineSendMessageRequest {
-        to: user_id.to_string(),
-        messages,
-    };
+pub async fn narrowcast_progress(query: NarrowcastProgressQuery) -> Result<impl Reply, Rejection> {
+    let token = get_secret("channel.token");
+    let result = request_handler::get_narrowcast_progress(&token, &query.request_id)
+        .await
+        .map_err(|e| e.to_string());
+    match result {
+        Ok(progress) => Ok(warp::reply::json(&json!({"success": true, "progress": progress})).into_response()),
+        Err(e) => {
+            log::error!("Narrowcast progress failed: {}", e);
+            Ok(handle_error_response("Error reading narrowcast progress").await)
+        }
+    }
+}
 
-    let json_body = serde_json::to_string(&request).unwrap();
+pub async fn broadcast_digest(query: SummaryQuery) -> Result<impl Reply, Rejection> {
+    if !schedule::should_run_today() {
+        return Ok(off_day_response("broadcastDigest"));
+    }
+    if quota::should_skip_optional_sends().await {
+        return Ok(quota_skipped_response("broadcastDigest"));
+    }
 
-    log::info!("{}", &json_body);
+    let token = get_secret("channel.token");
 
-    let url = get_config("message.push_url");
+    let stories = readrss::get_last_hn_stories().await;
+
+    let messages = if stories.is_empty() {
+        match empty_stories_notice(get_config_or("broadcast.send_empty_notice", false)).await {
+            Some(notice) => vec![notice],
+            None => return Ok(handle_error_response(NO_STORIES_ERROR).await),
+        }
+    } else {
+        stories::save_broadcast_snapshot(&stories);
+        let summary = get_chatgpt_summary(&stories, query.lang).await;
+        digest_messages(summary, &stories).await
+    };
 
-    request_handler::handle_send_request(token, json_body, url.as_str()).await
+    Ok(send_broadcast(&token, messages, stories.len()).await)
 }
 
+// The summary and the list it describes go out in one broadcast; split summary sections only
+// get the room the list leaves under LINE's five message limit
+async fn digest_messages(summary: String, stories: &[Story]) -> Vec<LineMessage> {
+    let mut story_messages = convert_stories_to_messages(stories).await;
+    let max_story_messages = line_helper::MAX_MESSAGES_PER_REQUEST - 1;
+    if story_messages.len() > max_story_messages {
+        log::warn!("Dropping {} story messages to leave room for the summary", story_messages.len() - max_story_messages);
+        story_messages.truncate(max_story_messages);
+    }
+    let room = line_helper::MAX_MESSAGES_PER_REQUEST - story_messages.len();
+    let mut messages = daily_summary_messages(summary, room).await;
+    messages.extend(story_messages);
+    messages.truncate(line_helper::MAX_MESSAGES_PER_REQUEST);
+    messages
+}
 
-async fn convert_stories_to_message() -> LineMessage {
-    let message_text = combine_stories().await;
+async fn empty_stories_notice(send_empty_notice: bool) -> Option<LineMessage> {
+    if !send_empty_notice {
+        return None;
+    }
 
-    let message = convert_to_line_message(message_text).await;
-    message
+    Some(convert_to_line_message(config_helper::get_prompt("prompt.no_stories")).await)
 }
 
-async fn combine_stories() -> String {
+async fn reply_latest_story(
+    token: &str,
+    reply_token: &str,
+    user_id: Option<&str>,
+) -> Result<impl Reply, Rejection> {
     let stories = readrss::get_last_hn_stories().await;
+    if let Some(user_id) = user_id {
+        stories::save_snapshot(user_id, &stories);
+    }
+    let messages = convert_stories_to_messages(&stories).await;
+
+    request_handler::handle_reply_request(token, reply_token, user_id, messages).await
+}
+
+async fn reply_daily_summary(
+    token: &str,
+    reply_token: &str,
+    user_id: Option<&str>,
+) -> Result<impl Reply, Rejection> {
+    let stories = readrss::get_last_hn_stories().await;
+    let messages = if stories.is_empty() {
+        vec![convert_to_line_message(config_helper::get_prompt("prompt.no_stories")).await]
+    } else {
+        daily_summary_messages(get_chatgpt_summary(&stories, None).await, line_helper::MAX_MESSAGES_PER_REQUEST).await
+    };
+
+    request_handler::handle_reply_request(token, reply_token, user_id, messages).await
+}
+
+async fn push_summary(
+    token: &str,
+    user_id: &str,
+    language_code: String,
+    indexes: Vec<StoryIndex>,
+    translate: bool,
+    include_original: bool,
+) -> Result<impl Reply, Rejection> {
+    let mut selected = Vec::new();
+    for index in indexes {
+        match stories::resolve_index(user_id, index).await {
+            Some(story) => selected.push(story),
+            None => log::warn!("Story {} is not in the list shown to the user", index.rank()),
+        }
+    }
+    let urls: Vec<String> = selected.iter().map(|story| story.storylink.clone()).collect();
+
+    if get_config_or("summary.delivery", "batched".to_string()).eq_ignore_ascii_case("incremental") {
+        let interval = Duration::from_millis(get_config_or("summary.push_interval_ms", DEFAULT_PUSH_INTERVAL_MS));
+        let summarize = |url: String| {
+            let language_code = language_code.clone();
+            async move {
+                let story_summary = summary::summarize_url_in_time(url.clone()).await;
+                let summary = localize_summary(story_summary.clone(), language_code, translate).await;
+                link_source(summary_messages(summary, story_summary, include_original), &url)
+            }
+        };
+        let push = |messages: Vec<String>| async move {
+            if push_messages(token, user_id, messages).await.is_err() {
+                handle_error_response("Error push summary").await;
+            }
+        };
+        deliver_incrementally(urls, || interval + send_delay(), summarize, push).await;
+        return Ok(warp::reply::json(&json!({"success": true})).into_response());
+    }
+
+    let story_summaries = summary::summarize_urls(urls.clone()).await;
+    if let Some(notice) = unavailable_notice(&story_summaries, || config_helper::get_prompt("prompt.summaries_unavailable")) {
+        return push_messages(token, user_id, notice).await.map(|reply| reply.into_response());
+    }
+    let story_summaries: Vec<String> = story_summaries.into_iter().map(|summary| summary.text).collect();
+
+    let summaries = localize_summaries(story_summaries.clone(), language_code, translate).await;
+    if export::is_enabled() && selected.len() > 1 {
+        match export::save(&export::render_digest(&selected, &summaries)) {
+            Ok(url) => {
+                let notice = config_helper::get_prompt("prompt.export_link").replace("{url}", &url);
+                return push_messages(token, user_id, vec![notice]).await.map(|reply| reply.into_response());
+            }
+            Err(e) => log::warn!("Failed to export the summaries, sending them as messages: {}", e),
+        }
+    }
+
+    let mut messages = Vec::new();
+    for ((summary, story_summary), url) in summaries.into_iter().zip(story_summaries).zip(&urls) {
+        messages.extend(link_source(summary_messages(summary, story_summary, include_original), url));
+    }
+
+    push_messages(token, user_id, messages).await.map(|reply| reply.into_response())
+}
+
+// One notice replaces the apologies when none of the stories could be summarized
+fn unavailable_notice(story_summaries: &[summary::SummaryResult], notice: impl FnOnce() -> String) -> Option<Vec<String>> {
+    if !summary::all_failed(story_summaries) {
+        return None;
+    }
+    log::warn!("All {} story summaries failed", story_summaries.len());
+    Some(vec![notice()])
+}
+
+// Summaries run concurrently but go out in index order, each as soon as it and the ones before it are
+// ready; the pause between pushes keeps a long list under LINE's rate limit
+async fn deliver_incrementally<D, S, SFut, P, PFut>(urls: Vec<String>, pause: D, summarize: S, mut push: P)
+where
+    D: Fn() -> Duration,
+    S: Fn(String) -> SFut,
+    SFut: Future<Output = Vec<String>>,
+    P: FnMut(Vec<String>) -> PFut,
+    PFut: Future<Output = ()>,
+{
+    let mut summaries: FuturesOrdered<SFut> = urls.into_iter().map(summarize).collect();
+    let mut first = true;
+    while let Some(messages) = summaries.next().await {
+        if !first {
+            tokio::time::sleep(pause()).await;
+        }
+        first = false;
+        push(messages).await;
+    }
+}
+
+async fn localize_summaries(summaries: Vec<String>, language_code: String, translate: bool) -> Vec<String> {
+    if !translate || is_summary_language(&language_code) {
+        return summaries;
+    }
+
+    if get_config_or("summary.batch", false) {
+        return chatgpt::translate_in_one_call(summaries, language_code).await;
+    }
+    chatgpt::translate_batch(summaries, language_code).await
+}
+
+async fn localize_summary(summary: String, language_code: String, translate: bool) -> String {
+    if !translate || is_summary_language(&language_code) {
+        return summary;
+    }
+
+    translate_or_keep(summary, language_code, translate_summary).await
+}
+
+async fn translate_summary(summary: String, language_code: String) -> Result<String, String> {
+    chatgpt::translate(summary, language_code).await.map_err(|e| e.to_string())
+}
+
+// Translation isn't covered by the token budget allow-list, so a failure keeps the untranslated summary
+async fn translate_or_keep<F, Fut>(summary: String, language_code: String, translate: F) -> String
+where
+    F: FnOnce(String, String) -> Fut,
+    Fut: Future<Output = Result<String, String>>,
+{
+    match translate(summary.clone(), language_code.clone()).await {
+        Ok(translated) => translated,
+        Err(e) => {
+            log::warn!("Failed to translate the summary to {}, sending it untranslated: {}", language_code, e);
+            summary
+        }
+    }
+}
+
+// The original follows the translation as its own message so readers can check it
+fn summary_messages(summary: String, original: String, include_original: bool) -> Vec<String> {
+    if !include_original || summary == original {
+        return vec![summary];
+    }
+
+    vec![summary, original_summary_message(original)]
+}
+
+fn original_summary_message(original: String) -> String {
+    match config_helper::get_optional_prompt("prompt.original_summary") {
+        Some(label) => format!("{}\n{}", label, original),
+        None => original,
+    }
+}
+
+// Story summaries already come out in these languages, Kagi's target language by default
+fn is_summary_language(language_code: &str) -> bool {
+    let native_languages = get_config_or("i18n.native_languages", vec![get_config("kagi.target_language")]);
+    is_native_language(language_code, &native_languages)
+}
+
+fn is_native_language(language_code: &str, native_languages: &[String]) -> bool {
+    let language = language_code.trim().to_lowercase();
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    native_languages
+        .iter()
+        .map(|native| native.to_lowercase())
+        .any(|native| native == language || native == primary)
+}
+
+async fn push_url_summary(
+    token: &str,
+    user_id: &str,
+    languages: Vec<String>,
+    url: String,
+    include_original: bool,
+) -> Result<impl Reply, Rejection> {
+    if article_is_flagged(&url).await {
+        let notice = config_helper::get_prompt("prompt.moderation_refused");
+        return push_messages(token, user_id, vec![notice]).await.map(|reply| reply.into_response());
+    }
+
+    let summary::SummaryResult { text: story_summary, provider } = summary::summarize_url(&url).await;
+
+    let mut messages = Vec::new();
+    for language_code in languages {
+        messages.push(localize_summary(story_summary.to_owned(), language_code, true).await);
+    }
+    if include_original && !messages.contains(&story_summary) {
+        messages.push(original_summary_message(story_summary));
+    }
+    let note = source_note(provider, get_config_or("summary.show_source", false), &config_helper::get_prompt("prompt.summary_fallback_note"));
+    if let (Some(note), Some(last)) = (note, messages.last_mut()) {
+        last.push_str(&format!("\n\n{}", note));
+    }
+    let messages = link_source(messages, &url);
+
+    push_messages(token, user_id, messages).await.map(|reply| reply.into_response())
+}
+
+// Only the fallback is called out; Kagi is the normal path and an apology speaks for itself
+fn source_note(provider: summary::SummaryProvider, show_source: bool, note: &str) -> Option<String> {
+    (show_source && provider == summary::SummaryProvider::ChatGptFallback).then(|| note.to_string())
+}
+
+// Pages that can't be fetched are left to the summarizer, which has its own fallbacks
+async fn article_is_flagged(url: &str) -> bool {
+    if !get_config_or("moderation.enabled", false) {
+        return false;
+    }
+    match content::fetch_article_text(url).await.map_err(|e| e.to_string()) {
+        Ok(text) => chatgpt::is_flagged(&text).await,
+        Err(e) => {
+            log::warn!("Could not fetch {} for moderation: {}", url, e);
+            false
+        }
+    }
+}
+
+fn link_source(messages: Vec<String>, url: &str) -> Vec<String> {
+    if !get_config_or("summary.link_source", false) {
+        return messages;
+    }
+    with_source_link(messages, &config_helper::get_prompt("prompt.read_original"), url)
+}
+
+// The link goes under the last message of a summary, after any translations and the original
+fn with_source_link(mut messages: Vec<String>, label: &str, url: &str) -> Vec<String> {
+    if let Some(last) = messages.last_mut() {
+        *last = line_helper::append_summary_footer(last, Some(label.to_string()), Some(url.to_string()));
+    }
+    messages
+}
+
+async fn push_messages(
+    token: &str,
+    user_id: &str,
+    text: Vec<String>,
+) -> Result<Response<Body>, Rejection> {
+    let messages: Vec<LineMessage> = text
+        .iter()
+        .map(|t| LineMessage {
+            message_type: "text".to_string(),
+            text: t.to_string(),
+            alt_text: None,
+            template: None,
+            sender: line_helper::get_configured_sender(),
+        })
+        .flat_map(line_helper::ensure_text_size)
+        .collect();
+
+    let url = get_config("message.push_url");
+    match send_push_chunks(token, &url, user_id, messages).await {
+        Ok(()) => Ok(warp::reply::json(&json!({"success": true})).into_response()),
+        Err(e) => {
+            log::error!("LINE push failed: {}", e);
+            Err(warp::reject::reject())
+        }
+    }
+}
+
+// LINE accepts at most five messages per push request; a rejected chunk stops the rest so the
+// user doesn't get a summary with a hole in it
+async fn send_push_chunks(token: &str, url: &str, user_id: &str, messages: Vec<LineMessage>) -> Result<(), String> {
+    let mut chunks = messages.chunks(line_helper::MAX_MESSAGES_PER_REQUEST).peekable();
+    loop {
+        let request = LineSendMessageRequest {
+            to: user_id.to_string(),
+            messages: chunks.next().unwrap_or_default().to_vec(),
+        };
+
+        let json_body = serde_json::to_string(&request).unwrap();
+
+        log::info!("{}", redact::redact(&json_body));
+
+        let response = request_handler::send_billed_request(token, json_body, url, &SendId::new())
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, redact::redact(&error_body)));
+        }
+        if chunks.peek().is_none() {
+            return Ok(());
+        }
+        tokio::time::sleep(send_delay()).await;
+    }
+}
+
+// Spacing between back-to-back sends to one user; the jitter keeps parallel deliveries from lining up
+fn send_delay() -> Duration {
+    delay_with_jitter(
+        get_config_or("line.send_delay_ms", 0),
+        get_config_or("line.send_jitter_ms", 0),
+        uuid::Uuid::new_v4().as_u128(),
+    )
+}
+
+fn delay_with_jitter(delay_ms: u64, jitter_ms: u64, random: u128) -> Duration {
+    let jitter = if jitter_ms == 0 { 0 } else { (random % (jitter_ms as u128 + 1)) as u64 };
+    Duration::from_millis(delay_ms + jitter)
+}
+
+async fn convert_stories_to_messages(stories: &[Story]) -> Vec<LineMessage> {
+    let link_style = get_config_or("line.link_style", "inline".to_string());
+    let labels: HashMap<String, String> = get_config_or("domains.labels", HashMap::new());
+    let stories = if get_config_or("line.translate_titles", false) {
+        with_translated_titles(&TITLE_TRANSLATIONS, stories, default_language(), chatgpt::try_translate_in_one_call).await
+    } else {
+        stories.to_vec()
+    };
+    let stories = with_domain_labels(&stories, &labels);
+    let stories = if get_config_or("line.mark_media_links", false) { with_media_markers(&stories) } else { stories };
+    let stories = if get_config_or("line.show_published", false) { with_published_times(&stories, Utc::now()) } else { stories };
+    if get_config_or("line.show_reading_time", false) {
+        let minutes = fetch_reading_times(&stories).await;
+        let stories = with_reading_times(&stories, &minutes, &config_helper::get_prompt("prompt.reading_time"));
+        return stories_to_messages(&stories, &link_style).await;
+    }
+    stories_to_messages(&stories, &link_style).await
+}
+
+// Titles not translated yet go out in one batch; the snapshot used for indexes keeps the originals.
+// Only successful translations are cached, so a failed call is retried on the next listing
+async fn with_translated_titles<F, Fut>(
+    cache: &std::sync::Mutex<HashMap<(String, String), String>>,
+    stories: &[Story],
+    language_code: String,
+    translate: F,
+) -> Vec<Story>
+where
+    F: FnOnce(Vec<String>, String) -> Fut,
+    Fut: Future<Output = Vec<Option<String>>>,
+{
+    let missing: Vec<String> = {
+        let cache = cache.lock().unwrap();
+        let mut seen = HashSet::new();
+        stories
+            .iter()
+            .map(|story| story.story.clone())
+            .filter(|title| !cache.contains_key(&(title.clone(), language_code.clone())) && seen.insert(title.clone()))
+            .collect()
+    };
+
+    if !missing.is_empty() {
+        let translations = translate(missing.clone(), language_code.clone()).await;
+        let mut cache = cache.lock().unwrap();
+        if cache.len() + missing.len() > MAX_CACHED_TITLE_TRANSLATIONS {
+            cache.clear();
+        }
+        for (title, translation) in missing.into_iter().zip(translations) {
+            if let Some(translation) = translation {
+                cache.insert((title, language_code.clone()), translation);
+            }
+        }
+    }
+
+    let cache = cache.lock().unwrap();
+    stories
+        .iter()
+        .map(|story| Story {
+            story: cache
+                .get(&(story.story.clone(), language_code.clone()))
+                .cloned()
+                .unwrap_or_else(|| story.story.clone()),
+            ..story.clone()
+        })
+        .collect()
+}
+
+// Video and image links are marked so they don't read like articles
+fn with_media_markers(stories: &[Story]) -> Vec<Story> {
+    stories
+        .iter()
+        .map(|story| Story {
+            story: match media::classify_link(&story.storylink).marker() {
+                Some(marker) => format!("{} {}", marker, story.story),
+                None => story.story.clone(),
+            },
+            ..story.clone()
+        })
+        .collect()
+}
+
+// Stories without their own date are left as they are
+fn with_published_times(stories: &[Story], now: DateTime<Utc>) -> Vec<Story> {
+    stories
+        .iter()
+        .map(|story| Story {
+            story: match story.published {
+                Some(published) => format!("{} · {}", story.story, stories::relative_time(published, now)),
+                None => story.story.clone(),
+            },
+            ..story.clone()
+        })
+        .collect()
+}
+
+fn with_domain_labels(stories: &[Story], labels: &HashMap<String, String>) -> Vec<Story> {
+    stories
+        .iter()
+        .map(|story| Story {
+            story: match stories::domain_label(&story.storylink, labels) {
+                Some(label) => format!("{} [{}]", story.story, label),
+                None => story.story.clone(),
+            },
+            ..story.clone()
+        })
+        .collect()
+}
+
+// Articles that can't be fetched in time are listed without an estimate
+async fn fetch_reading_times(stories: &[Story]) -> Vec<Option<u32>> {
+    let fetches = stories.iter().map(|story| async move {
+        match tokio::time::timeout(READING_TIME_FETCH_TIMEOUT, content::fetch_article_text(&story.storylink)).await {
+            Ok(Ok(text)) => Some(content::estimate_reading_minutes(&text)),
+            _ => None,
+        }
+    });
+    futures::future::join_all(fetches).await
+}
+
+fn with_reading_times(stories: &[Story], minutes: &[Option<u32>], template: &str) -> Vec<Story> {
+    stories
+        .iter()
+        .zip(minutes)
+        .map(|(story, minutes)| Story {
+            story: match minutes {
+                Some(minutes) => format!("{} {}", story.story, template.replace("{minutes}", &minutes.to_string())),
+                None => story.story.clone(),
+            },
+            ..story.clone()
+        })
+        .collect()
+}
+
+// Buttons keep long URLs out of the chat; a request holds five messages, so at most 20 stories get buttons
+async fn stories_to_messages(stories: &[Story], link_style: &str) -> Vec<LineMessage> {
+    match link_style {
+        "button" if !stories.is_empty() => {
+            let mut messages = line_helper::create_buttons_template(stories, line_helper::get_configured_sender());
+            if messages.len() > line_helper::MAX_MESSAGES_PER_REQUEST {
+                log::warn!(
+                    "Only the first {} of {} stories fit in button messages",
+                    line_helper::MAX_MESSAGES_PER_REQUEST * line_helper::MAX_BUTTONS_ACTIONS,
+                    stories.len()
+                );
+                messages.truncate(line_helper::MAX_MESSAGES_PER_REQUEST);
+            }
+            messages
+        }
+        _ => vec![convert_stories_to_message(stories).await],
+    }
+}
+
+async fn convert_stories_to_message(stories: &[Story]) -> LineMessage {
+    let message_text = combine_stories(stories);
+
+    let message = convert_to_line_message(message_text).await;
+    message
+}
+
+fn combine_stories(stories: &[Story]) -> String {
     let message_text = stories
         .iter()
         .enumerate()
@@ -378,20 +1409,823 @@ async fn combine_stories() -> String {
     message_text
 }
 
-async fn get_chatgpt_summary() -> LineMessage {
-    let stories = combine_stories().await;
-    let summary = chatgpt::get_chatgpt_summary(stories).await.unwrap();
+async fn get_chatgpt_summary(stories: &[Story], language: Option<String>) -> String {
+    let summary = build_daily_summary(stories, language).await;
+    line_helper::get_configured_summary_footer(&summary)
+}
+
+// With summary.split_sections, each paragraph of the summary goes out as its own message
+async fn daily_summary_messages(summary: String, max_messages: usize) -> Vec<LineMessage> {
+    if get_config_or("summary.split_sections", false) {
+        return line_helper::create_summary_section_messages(&summary, max_messages, line_helper::get_configured_sender());
+    }
+    vec![convert_to_line_message(summary).await]
+}
+
+// Generates the daily summary ahead of /broadcastDailySummary so the broadcast only sends the cached text
+pub async fn run_summary_warmup() {
+    if get_config_or("schedule.warmup_on_start", false) {
+        warm_daily_summary(warm_latest_stories).await;
+    }
+
+    let Some(broadcast_time) = get_optional_config("schedule.broadcast_time") else {
+        return;
+    };
+    let Ok(broadcast_time) = NaiveTime::parse_from_str(broadcast_time.trim(), "%H:%M") else {
+        log::error!("schedule.broadcast_time {:?} is not HH:MM, summary warm-up disabled", broadcast_time);
+        return;
+    };
+    let lead = chrono::Duration::minutes(get_config_or("schedule.warmup_lead_minutes", DEFAULT_WARMUP_LEAD_MINUTES));
+
+    loop {
+        let timezone = summary::configured_timezone();
+        warm_up_next(&SystemClock, timezone, broadcast_time, lead, schedule::should_run_on, warm_latest_stories).await;
+    }
+}
+
+// Waits for the next warm-up on clock, then warms unless the schedule skips that broadcast
+async fn warm_up_next<S, F, Fut>(clock: &dyn Clock, timezone: Tz, broadcast_time: NaiveTime, lead: chrono::Duration, should_run: S, warm: F)
+where
+    S: Fn(DateTime<Utc>) -> bool,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let now = clock.now_utc();
+    let warmup_at = summary::next_warmup(now, timezone, broadcast_time, lead);
+    log::info!("Next daily summary warm-up at {}", warmup_at);
+    tokio::time::sleep((warmup_at - now).to_std().unwrap_or_default()).await;
+    if should_run(warmup_at + lead) {
+        warm_daily_summary(warm).await;
+    }
+}
+
+// Runs in its own task so a failed feed or ChatGPT call can't end the warm-up loop
+async fn warm_daily_summary<F, Fut>(warm: F)
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    match tokio::spawn(warm()).await {
+        Ok(()) => log::info!("Daily summary warmed up"),
+        Err(e) => log::error!("Daily summary warm-up failed: {}", e),
+    }
+}
+
+async fn warm_latest_stories() {
+    let stories = readrss::get_last_hn_stories().await;
+    if !stories.is_empty() {
+        build_daily_summary(&stories, None).await;
+    }
+}
+
+// Previews and broadcasts of the same stories on the same day share one generated summary
+async fn build_daily_summary(stories: &[Story], language: Option<String>) -> String {
+    let key = format!("{}|{}", language.as_deref().unwrap_or_default(), combine_stories(stories));
+    summary::cached_daily_summary(&key, || generate_daily_summary(stories, language)).await
+}
+
+// The cached text is already formatted, so previews show what the broadcast sends
+async fn generate_daily_summary(stories: &[Story], language: Option<String>) -> String {
+    let summary = generate_summary_text(stories, language.clone(), chatgpt::configured_summary_prompt()).await;
+    let summary = if get_config_or("summary.grouped", false) {
+        let sentiment_icons = get_config_or("summary.sentiment_icons", false);
+        grouped_or_flat(summary, sentiment_icons, || generate_summary_text(stories, language, chatgpt::flat_summary_prompt())).await
+    } else {
+        summary
+    };
+
+    if get_config_or("summary.style", "prose".to_string()).eq_ignore_ascii_case("bullets") {
+        return line_helper::format_bullets(&summary);
+    }
+    summary
+}
+
+// A grouped answer that isn't the expected JSON is replaced by a flat summary rather than sent raw
+async fn grouped_or_flat<F, Fut>(summary: String, sentiment_icons: bool, flat: F) -> String
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = String>,
+{
+    match line_helper::format_grouped_summary(&summary, sentiment_icons) {
+        Some(grouped) => grouped,
+        None => {
+            log::warn!("Grouped summary is not valid JSON, generating a flat summary instead");
+            flat().await
+        }
+    }
+}
+
+async fn generate_summary_text(stories: &[Story], language: Option<String>, prompt_key: &str) -> String {
+    let max_input_tokens = get_config_or("summary.max_input_tokens", DEFAULT_SUMMARY_MAX_INPUT_TOKENS);
+    let stories = stories_within_budget(stories, max_input_tokens);
+    let generation_language = get_config_or("summary.language", "zh-tw".to_string());
+    let target_language = summary_translation_target(language, &generation_language);
+
+    let requested_language = target_language.as_deref().unwrap_or(&generation_language);
+    if let Some(summary) = chatgpt::get_chatgpt_summary_in(combine_stories(stories), requested_language, prompt_key).await {
+        let summary = summary.unwrap();
+        log::info!("summary message: {}", summary);
+        return summary;
+    }
+
+    let summary = chatgpt::get_chatgpt_summary(combine_stories(stories), prompt_key).await.unwrap();
 
     log::info!("summary message: {}", summary);
 
-    let message = convert_to_line_message(summary).await;
-    message
+    match target_language {
+        Some(language) => translate_or_keep(summary, language, translate_summary).await,
+        None => summary,
+    }
+}
+
+// Stories are ranked, so the ones dropped to fit the prompt come from the end of the list
+fn stories_within_budget(stories: &[Story], max_tokens: usize) -> &[Story] {
+    let mut count = stories.len();
+    while count > 1 && content::estimate_tokens(&combine_stories(&stories[..count])) > max_tokens {
+        count -= 1;
+    }
+    if count < stories.len() {
+        log::warn!(
+            "Dropped {} of {} stories to keep the summary prompt under {} tokens",
+            stories.len() - count,
+            stories.len(),
+            max_tokens
+        );
+    }
+    &stories[..count]
+}
+
+fn summary_translation_target(requested: Option<String>, generation_language: &str) -> Option<String> {
+    requested
+        .map(|language| language.trim().to_lowercase())
+        .filter(|language| !language.is_empty() && !language.eq_ignore_ascii_case(generation_language))
 }
 
 async fn convert_to_line_message(summary: String) -> LineMessage {
-    let message = LineMessage {
+    LineMessage {
         message_type: "text".to_string(),
         text: summary,
-    };
-    message
+        alt_text: None,
+        template: None,
+        sender: line_helper::get_configured_sender(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_push_summary_arguments_defaults_translate() {
+        let arguments = parse_push_summary_arguments("{\"indexes\": [1, 3]}");
+
+        assert_eq!(arguments.indexes, vec![1, 3]);
+        assert!(arguments.translate);
+    }
+
+    #[tokio::test]
+    async fn test_localize_summary_skips_translation() {
+        let arguments = parse_push_summary_arguments("{\"indexes\": [2], \"translate\": false}");
+        assert!(!arguments.translate);
+
+        let summary = "An English summary.".to_string();
+        let result = localize_summary(summary.clone(), "ja".to_string(), arguments.translate).await;
+
+        assert_eq!(result, summary);
+    }
+
+    #[tokio::test]
+    async fn test_spent_budget_keeps_untranslated_summary() {
+        let summary = "An English summary.".to_string();
+        let result = translate_or_keep(summary.clone(), "ja".to_string(), |_, _| async {
+            Err(chatgpt::BUDGET_REACHED.to_string())
+        })
+        .await;
+        assert_eq!(result, summary);
+
+        let result = translate_or_keep(summary, "ja".to_string(), |_, _| async { Ok("日本語の要約。".to_string()) }).await;
+        assert_eq!(result, "日本語の要約。");
+    }
+
+    #[tokio::test]
+    async fn test_push_url_summary_with_two_languages() {
+        let arguments = parse_push_url_summary_arguments("{\"url\": \"https://example.com\", \"languages\": [\"en\", \"JA\"]}");
+
+        let languages = resolve_summary_languages(arguments.languages, "zh-tw".to_string());
+        assert_eq!(languages, vec!["en".to_string(), "ja".to_string()]);
+
+        let summary = "An English summary.".to_string();
+        let result = localize_summary(summary.clone(), languages[0].clone(), true).await;
+        assert_eq!(result, summary);
+    }
+
+    #[test]
+    fn test_detection_failure_uses_default_language() {
+        let configured = default_language();
+
+        assert_eq!(language_or_default(Err("timeout".to_string()), configured.clone()), configured);
+        assert_eq!(language_or_default(Ok("  ".to_string()), "ja".to_string()), "ja");
+        assert_eq!(language_or_default(Ok("ko\n".to_string()), "ja".to_string()), "ko");
+    }
+
+    #[test]
+    fn test_native_languages_skip_translation() {
+        let native = vec!["EN".to_string(), "ja".to_string()];
+
+        assert!(is_native_language("en-US", &native));
+        assert!(is_native_language("ja", &native));
+        assert!(!is_native_language("zh-tw", &native));
+    }
+
+    #[test]
+    fn test_unsupported_language_uses_default() {
+        let supported = vec!["zh-tw".to_string(), "en".to_string(), "ja".to_string()];
+
+        assert_eq!(resolve_output_language("tlh", &supported, "zh-tw"), "zh-tw");
+        assert_eq!(resolve_output_language("zh-CN", &supported, "zh-tw"), "zh-tw");
+        assert_eq!(resolve_output_language("en-US", &supported, "zh-tw"), "en-us");
+        assert_eq!(resolve_output_language("JA", &supported, "zh-tw"), "ja");
+        assert_eq!(resolve_output_language("tlh", &[], "zh-tw"), "tlh");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_result_echoes_story_count() {
+        let stories: Vec<Story> = (1..=3)
+            .map(|i| Story {
+                storylink: format!("https://example.com/{}", i),
+                story: format!("Story {}", i),
+                published: None,
+            })
+            .collect();
+        let message = convert_stories_to_message(&stories).await;
+        let broadcast_at = "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let result = serde_json::to_value(BroadcastResult::new(&[message], stories.len(), broadcast_at)).unwrap();
+
+        assert_eq!(
+            result,
+            json!({
+                "success": true,
+                "story_count": 3,
+                "message_count": 1,
+                "message_type": "text",
+                "broadcast_at": "2024-05-01T00:00:00Z",
+            })
+        );
+    }
+
+    #[test]
+    fn test_story_at_valid_and_out_of_range() {
+        let stories = vec![
+            Story {
+                storylink: "https://example.com/1".to_string(),
+                story: "First".to_string(),
+                published: None,
+            },
+            Story {
+                storylink: "https://example.com/2".to_string(),
+                story: "Second".to_string(),
+                published: None,
+            },
+        ];
+
+        assert_eq!(story_at(&stories, 2).unwrap().story, "Second");
+        assert!(story_at(&stories, 0).is_none());
+        assert!(story_at(&stories, 3).is_none());
+        assert_eq!(story_index_error(stories.len()).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_story_summary_query_params() {
+        let query = warp::test::request()
+            .path("/summarizeStory?index=3&lang=ja")
+            .filter(&story_summary_query())
+            .await
+            .unwrap();
+        assert_eq!(query.index, 3);
+        assert_eq!(query.lang, Some("ja".to_string()));
+
+        let query = warp::test::request()
+            .path("/summarizeStory?index=1")
+            .filter(&story_summary_query())
+            .await
+            .unwrap();
+        assert_eq!(query.lang, None);
+
+        assert!(warp::test::request()
+            .path("/summarizeStory?lang=ja")
+            .filter(&story_summary_query())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_digest_sends_summary_and_stories_together() {
+        let stories = vec![
+            Story {
+                storylink: "https://example.com/rust".to_string(),
+                story: "Rust 2.0 released".to_string(),
+                published: None,
+            },
+            Story {
+                storylink: "https://example.com/line".to_string(),
+                story: "LINE bots in practice".to_string(),
+                published: None,
+            },
+        ];
+        let summary = "今日摘要".to_string();
+
+        let request = LineBroadcastRequest {
+            messages: digest_messages(summary, &stories).await,
+        };
+
+        assert_eq!(request.messages.len(), 2);
+        assert!(request.messages.len() <= line_helper::MAX_MESSAGES_PER_REQUEST);
+        assert_eq!(request.messages[0].text, "今日摘要");
+        assert_eq!(request.messages[1].text, combine_stories(&stories));
+    }
+
+    #[tokio::test]
+    async fn test_inline_link_style() {
+        let stories = sample_stories(5);
+
+        let messages = stories_to_messages(&stories, "inline").await;
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_type, "text");
+        assert!(messages[0].text.contains("(https://example.com/5)"));
+    }
+
+    #[tokio::test]
+    async fn test_button_link_style() {
+        let stories = sample_stories(5);
+
+        let messages = stories_to_messages(&stories, "button").await;
+
+        assert_eq!(messages.len(), 2);
+        let message = serde_json::to_value(&messages[0]).unwrap();
+        assert_eq!(message["type"], "template");
+        assert_eq!(message["template"]["type"], "buttons");
+        assert_eq!(message["template"]["actions"].as_array().unwrap().len(), 4);
+        assert_eq!(message["template"]["actions"][0]["type"], "uri");
+        assert_eq!(message["template"]["actions"][0]["uri"], "https://example.com/1");
+        assert!(message.get("text").is_none());
+        let last = serde_json::to_value(&messages[1]).unwrap();
+        assert_eq!(last["template"]["actions"][0]["label"], "5. Story 5");
+
+        let long_titles: Vec<Story> = (1..=4)
+            .map(|i| Story { storylink: format!("https://example.com/{}", i), story: "a very long title ".repeat(20), published: None })
+            .collect();
+        let messages = stories_to_messages(&long_titles, "button").await;
+        assert!(messages[0].alt_text.as_ref().unwrap().chars().count() <= 400);
+    }
+
+    #[test]
+    fn test_group_source_is_skipped_when_only_users_allowed() {
+        let body: Value = serde_json::from_str(r#"{"events": [{"type": "message", "source": {"type": "group", "groupId": "G1", "userId": "U1"}}]}"#).unwrap();
+        let source_type = body["events"][0]["source"]["type"].as_str();
+        let users_only = vec!["user".to_string()];
+
+        assert!(!is_allowed_source(source_type, &users_only));
+        assert!(is_allowed_source(Some("user"), &users_only));
+        assert!(!is_allowed_source(None, &users_only));
+        assert!(is_allowed_source(source_type, &[]));
+    }
+
+    #[tokio::test]
+    async fn test_translated_titles_are_listed_and_cached() {
+        let cache = std::sync::Mutex::new(HashMap::new());
+        let stories = vec![
+            Story { storylink: "https://example.com/rust".to_string(), story: "Rust 2.0 released".to_string(), published: None },
+            Story { storylink: "https://example.com/sqlite".to_string(), story: "SQLite internals".to_string(), published: None },
+        ];
+        let calls = std::sync::Mutex::new(Vec::new());
+        let translate = |titles: Vec<String>, _language_code: String| {
+            calls.lock().unwrap().push(titles.clone());
+            async move { titles.iter().map(|title| Some(format!("譯：{}", title))).collect::<Vec<Option<String>>>() }
+        };
+
+        let translated = with_translated_titles(&cache, &stories, "zh-tw".to_string(), translate).await;
+        assert_eq!(
+            combine_stories(&translated),
+            "1. 譯：Rust 2.0 released (https://example.com/rust)\n\n2. 譯：SQLite internals (https://example.com/sqlite)"
+        );
+
+        let translated = with_translated_titles(&cache, &stories, "zh-tw".to_string(), translate).await;
+        assert_eq!(translated[0].story, "譯：Rust 2.0 released");
+        assert_eq!(calls.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_title_translations_are_not_cached() {
+        let cache = std::sync::Mutex::new(HashMap::new());
+        let story = |title: &str| Story { storylink: format!("https://example.com/{}", title), story: title.to_string(), published: None };
+        let stories = vec![story("Rust"), story("SQLite"), story("Rust")];
+
+        let translated = with_translated_titles(&cache, &stories, "zh-tw".to_string(), |titles: Vec<String>, _| async move {
+            assert_eq!(titles, vec!["Rust", "SQLite"]);
+            vec![Some("譯：Rust".to_string()), None]
+        })
+        .await;
+        assert_eq!(translated.iter().map(|story| story.story.as_str()).collect::<Vec<&str>>(), vec!["譯：Rust", "SQLite", "譯：Rust"]);
+
+        with_translated_titles(&cache, &stories, "zh-tw".to_string(), |titles: Vec<String>, _| async move {
+            assert_eq!(titles, vec!["SQLite"]);
+            vec![Some("譯：SQLite".to_string())]
+        })
+        .await;
+        assert_eq!(cache.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_source_note_only_on_fallback() {
+        use summary::SummaryProvider;
+        let note = "（此摘要由備援方式產生）";
+
+        assert_eq!(source_note(SummaryProvider::ChatGptFallback, true, note), Some(note.to_string()));
+        assert_eq!(source_note(SummaryProvider::Kagi, true, note), None);
+        assert_eq!(source_note(SummaryProvider::Unavailable, true, note), None);
+        assert_eq!(source_note(SummaryProvider::ChatGptFallback, false, note), None);
+    }
+
+    #[tokio::test]
+    async fn test_failed_chunk_stops_later_chunks() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = requests.clone();
+        let push = warp::post().and(warp::path("push")).map(move || {
+            counted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            warp::reply::with_status(r#"{"message":"The request body has 1 error(s)"}"#, StatusCode::BAD_REQUEST)
+        });
+        let (addr, server) = warp::serve(push).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let messages: Vec<LineMessage> = futures::future::join_all((1..=7).map(|i| convert_to_line_message(format!("摘要 {}", i)))).await;
+        let result = send_push_chunks("token", &format!("http://{}/push", addr), "U1", messages).await;
+
+        assert!(result.unwrap_err().starts_with("400"));
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_populates_cache() {
+        use chrono::TimeZone;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let broadcast_time = NaiveTime::from_hms_opt(8, 0, 0).unwrap();
+        let lead = chrono::Duration::minutes(15);
+        let clock = crate::clock::MockClock::new(Utc.with_ymd_and_hms(2026, 10, 16, 7, 44, 59).unwrap());
+        clock.advance(Duration::from_millis(970));
+
+        let key = "warm-up-test|stories";
+        let warmed = Arc::new(AtomicUsize::new(0));
+        let warm = |warmed: Arc<AtomicUsize>| {
+            move || async move {
+                warmed.fetch_add(1, Ordering::SeqCst);
+                summary::cached_daily_summary(key, || async { "預先產生的摘要".to_string() }).await;
+            }
+        };
+
+        let skipped_day = |broadcast: DateTime<Utc>| {
+            assert_eq!(broadcast, Utc.with_ymd_and_hms(2026, 10, 16, 8, 0, 0).unwrap());
+            false
+        };
+        warm_up_next(&clock, Tz::UTC, broadcast_time, lead, skipped_day, warm(warmed.clone())).await;
+        assert_eq!(warmed.load(Ordering::SeqCst), 0);
+
+        warm_up_next(&clock, Tz::UTC, broadcast_time, lead, |_| true, warm(warmed.clone())).await;
+        assert_eq!(warmed.load(Ordering::SeqCst), 1);
+        let broadcast = summary::cached_daily_summary(key, || async { "不該重新產生".to_string() }).await;
+        assert_eq!(broadcast, "預先產生的摘要");
+
+        // A warm-up that panics is logged instead of ending the loop
+        warm_daily_summary(|| async { panic!("feed unavailable") }).await;
+    }
+
+    #[test]
+    fn test_failed_summaries_push_one_notice() {
+        use summary::{SummaryProvider, SummaryResult};
+
+        let failed = vec![
+            SummaryResult { text: "摘要生成失敗".to_string(), provider: SummaryProvider::TimedOut },
+            SummaryResult { text: "抱歉，這篇文章無法摘要。".to_string(), provider: SummaryProvider::Unavailable },
+            SummaryResult { text: "抱歉，這篇文章無法摘要。".to_string(), provider: SummaryProvider::Unavailable },
+        ];
+        assert_eq!(unavailable_notice(&failed, || "暫時無法摘要".to_string()), Some(vec!["暫時無法摘要".to_string()]));
+
+        let mut partial = failed;
+        partial[1] = SummaryResult { text: "The scheduler got faster.".to_string(), provider: SummaryProvider::ChatGptFallback };
+        assert_eq!(unavailable_notice(&partial, || unreachable!()), None);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_grouped_summary_falls_back_to_flat() {
+        let flat = grouped_or_flat(r#"{"groups": [{"topic": "AI""#.to_string(), false, || async { "今日重點".to_string() }).await;
+        assert_eq!(flat, "今日重點");
+
+        let grouped = grouped_or_flat(
+            r#"{"groups": [{"topic": "AI", "items": ["新模型"]}]}"#.to_string(),
+            false,
+            || async { panic!("should not regenerate") },
+        )
+        .await;
+        assert_eq!(grouped, "【AI】\n• 新模型");
+    }
+
+    #[tokio::test]
+    async fn test_delay_between_sends() {
+        let urls: Vec<String> = (1..=3).map(|i| format!("https://example.com/{}", i)).collect();
+        let sent_at = std::sync::Mutex::new(Vec::new());
+
+        deliver_incrementally(
+            urls,
+            || delay_with_jitter(30, 0, 0),
+            |url| async move { vec![url] },
+            |_| {
+                sent_at.lock().unwrap().push(Instant::now());
+                async {}
+            },
+        )
+        .await;
+
+        let sent_at = sent_at.into_inner().unwrap();
+        assert_eq!(sent_at.len(), 3);
+        assert!(sent_at.windows(2).all(|pair| pair[1] - pair[0] >= Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn test_delay_jitter_stays_in_range() {
+        assert_eq!(delay_with_jitter(0, 0, 12345), Duration::ZERO);
+        assert_eq!(delay_with_jitter(200, 0, 12345), Duration::from_millis(200));
+        assert!((0..1000u128).all(|random| {
+            let delay = delay_with_jitter(200, 50, random);
+            delay >= Duration::from_millis(200) && delay <= Duration::from_millis(250)
+        }));
+    }
+
+    #[test]
+    fn test_stories_trimmed_to_token_budget() {
+        let stories: Vec<Story> = (1..=10)
+            .map(|i| Story {
+                storylink: format!("https://example.com/{}", i),
+                story: format!("Story {} {}", i, "with a very long title ".repeat(10)),
+                published: None,
+            })
+            .collect();
+
+        let trimmed = stories_within_budget(&stories, 200);
+
+        assert!(trimmed.len() < stories.len());
+        assert!(content::estimate_tokens(&combine_stories(trimmed)) <= 200);
+        assert!(content::estimate_tokens(&combine_stories(&stories[..trimmed.len() + 1])) > 200);
+        assert_eq!(trimmed[0].storylink, "https://example.com/1");
+        assert_eq!(stories_within_budget(&stories, 100_000).len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_video_story_is_marked() {
+        let stories = vec![
+            Story {
+                storylink: "https://www.youtube.com/watch?v=abc123".to_string(),
+                story: "Demo of the new debugger".to_string(),
+                published: None,
+            },
+            Story {
+                storylink: "https://example.com/article".to_string(),
+                story: "An article".to_string(),
+                published: None,
+            },
+        ];
+
+        let messages = stories_to_messages(&with_media_markers(&stories), "inline").await;
+
+        assert_eq!(
+            messages[0].text,
+            "1. ▶️ Demo of the new debugger (https://www.youtube.com/watch?v=abc123)\n\n2. An article (https://example.com/article)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_domain_labels_in_story_list() {
+        let labels = HashMap::from([("example.com".to_string(), "paywalled".to_string())]);
+        let stories = vec![
+            Story {
+                storylink: "https://www.example.com/article".to_string(),
+                story: "Paywalled story".to_string(),
+                published: None,
+            },
+            Story {
+                storylink: "https://github.com/rust-lang/rust".to_string(),
+                story: "Open story".to_string(),
+                published: None,
+            },
+        ];
+
+        let messages = stories_to_messages(&with_domain_labels(&stories, &labels), "inline").await;
+
+        assert_eq!(
+            messages[0].text,
+            "1. Paywalled story [paywalled] (https://www.example.com/article)\n\n2. Open story (https://github.com/rust-lang/rust)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reading_time_in_story_list() {
+        let stories = with_reading_times(&sample_stories(2), &[Some(5), None], "~{minutes} min read");
+
+        let messages = stories_to_messages(&stories, "inline").await;
+
+        assert_eq!(
+            messages[0].text,
+            "1. Story 1 ~5 min read (https://example.com/1)\n\n2. Story 2 (https://example.com/2)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_route_returns_404() {
+        let route = |enabled: bool| warp::path("conversation").and(route_gate(move || enabled)).map(|| "ok");
+
+        let response = warp::test::request().path("/conversation").reply(&route(false)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = warp::test::request().path("/conversation").reply(&route(true)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_stories_follow_fetched_stories() {
+        let pinned = vec![PinnedStory {
+            title: "Community guidelines".to_string(),
+            url: "https://example.com/guidelines".to_string(),
+        }];
+
+        let messages = stories_to_messages(&sample_stories(2), "inline").await;
+        let messages = with_pinned_stories(messages, &pinned).await;
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].text, "1. Story 1 (https://example.com/1)\n\n2. Story 2 (https://example.com/2)");
+        assert_eq!(messages[1].text, "📌 Community guidelines (https://example.com/guidelines)");
+    }
+
+    #[tokio::test]
+    async fn test_incremental_delivery_pushes_each_summary() {
+        let urls = vec!["https://example.com/slow".to_string(), "https://example.com/fast".to_string()];
+        let pushes = std::sync::Mutex::new(Vec::new());
+
+        deliver_incrementally(
+            urls,
+            || Duration::ZERO,
+            |url| async move {
+                if url.ends_with("slow") {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                vec![format!("Summary of {}", url)]
+            },
+            |messages| {
+                pushes.lock().unwrap().push(messages);
+                async {}
+            },
+        )
+        .await;
+
+        assert_eq!(
+            pushes.into_inner().unwrap(),
+            vec![vec!["Summary of https://example.com/slow"], vec!["Summary of https://example.com/fast"]]
+        );
+    }
+
+    #[test]
+    fn test_source_link_under_summary() {
+        let messages = vec!["Rust 2.0 摘要".to_string(), "Original: Rust 2.0 summary".to_string()];
+
+        let messages = with_source_link(messages, "閱讀原文", "https://example.com/rust");
+
+        assert_eq!(messages[0], "Rust 2.0 摘要");
+        assert_eq!(messages[1], "Original: Rust 2.0 summary\n\n閱讀原文\nhttps://example.com/rust");
+        assert!(with_source_link(Vec::new(), "閱讀原文", "https://example.com/rust").is_empty());
+    }
+
+    fn sample_stories(count: usize) -> Vec<Story> {
+        (1..=count)
+            .map(|i| Story {
+                storylink: format!("https://example.com/{}", i),
+                story: format!("Story {}", i),
+                published: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unknown_function_notice() {
+        assert_eq!(
+            unknown_function_notice(&None),
+            Some(config_helper::get_prompt("prompt.unknown_function"))
+        );
+
+        let message = Some("I can only help with Hacker News stories.".to_string());
+        assert_eq!(unknown_function_notice(&message), None);
+    }
+
+    #[test]
+    fn test_summary_messages_include_original() {
+        let arguments = parse_push_summary_arguments("{\"indexes\": [1], \"include_original\": true}");
+        assert!(resolve_include_original(arguments.include_original));
+
+        let messages = summary_messages("翻譯後的摘要。".to_string(), "An English summary.".to_string(), true);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], "翻譯後的摘要。");
+        assert!(messages[1].ends_with("An English summary."));
+
+        let messages = summary_messages("翻譯後的摘要。".to_string(), "An English summary.".to_string(), false);
+        assert_eq!(messages, vec!["翻譯後的摘要。".to_string()]);
+    }
+
+    #[test]
+    fn test_include_original_defaults_to_config() {
+        let arguments = parse_push_url_summary_arguments("{\"url\": \"https://example.com\"}");
+
+        assert!(!resolve_include_original(arguments.include_original));
+    }
+
+    #[tokio::test]
+    async fn test_empty_stories_notice() {
+        let notice = empty_stories_notice(true).await.unwrap();
+        assert_eq!(notice.text, config_helper::get_prompt("prompt.no_stories"));
+
+        assert!(empty_stories_notice(false).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_summary_query_lang_param() {
+        let query = warp::test::request()
+            .path("/previewDailySummary?lang=ja")
+            .filter(&summary_query())
+            .await
+            .unwrap();
+        assert_eq!(summary_translation_target(query.lang, "zh-tw"), Some("ja".to_string()));
+
+        let query = warp::test::request()
+            .path("/previewDailySummary")
+            .filter(&summary_query())
+            .await
+            .unwrap();
+        assert_eq!(summary_translation_target(query.lang, "zh-tw"), None);
+    }
+
+    #[test]
+    fn test_summary_translation_target_skips_generation_language() {
+        assert_eq!(summary_translation_target(Some("ZH-TW".to_string()), "zh-tw"), None);
+        assert_eq!(summary_translation_target(Some(" ".to_string()), "zh-tw"), None);
+    }
+
+    #[test]
+    fn test_hello_response_modes() {
+        assert_eq!(hello_response(false, Duration::from_secs(42)), json!({"success": true}));
+
+        let debug = hello_response(true, Duration::from_secs(42));
+        assert_eq!(debug["success"], true);
+        assert_eq!(debug["uptime_secs"], 42);
+        assert_eq!(debug["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_run_test_prompt_unknown_key() {
+        let request = TestPromptRequest {
+            prompt_key: "prompt.does_not_exist".to_string(),
+            content: "hello".to_string(),
+        };
+
+        let response = run_test_prompt(request).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_narrowcast_requires_a_target() {
+        let request = NarrowcastRequest { recipient: None, filter: None, lang: None };
+
+        let response = narrowcast_daily_summary(request).await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_limit_indexes_clamps_and_deduplicates() {
+        let (indexes, trimmed) = limit_indexes(vec![3, 1, 3, 2, 7, 9, 4, 8], 5);
+
+        assert_eq!(indexes, vec![3, 1, 2, 7, 9]);
+        assert!(trimmed);
+
+        let (indexes, trimmed) = limit_indexes(vec![2, 2, 1], 5);
+        assert_eq!(indexes, vec![2, 1]);
+        assert!(!trimmed);
+    }
+
+    #[test]
+    fn test_resolve_summary_languages_bounds_and_defaults() {
+        let requested = ["en", "ja", "en", "ko", "fr"].iter().map(|l| l.to_string()).collect();
+        assert_eq!(resolve_summary_languages(requested, "zh-tw".to_string()), vec!["en", "ja", "ko"]);
+
+        assert_eq!(resolve_summary_languages(Vec::new(), "zh-tw".to_string()), vec!["zh-tw"]);
+    }
 }
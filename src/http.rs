@@ -0,0 +1,59 @@
+use once_cell::sync::Lazy;
+use tokio::sync::Semaphore;
+
+use crate::config_helper::get_config_or;
+
+const DEFAULT_MAX_GLOBAL_CONCURRENCY: usize = 16;
+
+// One cap for every outbound request, whether it goes to the feed, ChatGPT, Kagi or LINE
+static GLOBAL_PERMITS: Lazy<Semaphore> = Lazy::new(|| {
+    Semaphore::new(get_config_or("http.max_global_concurrency", DEFAULT_MAX_GLOBAL_CONCURRENCY).max(1))
+});
+
+pub async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, reqwest::Error> {
+    send_with(&GLOBAL_PERMITS, request).await
+}
+
+async fn send_with(permits: &Semaphore, request: reqwest::RequestBuilder) -> Result<reqwest::Response, reqwest::Error> {
+    let _permit = permits.acquire().await.expect("HTTP semaphore closed");
+    request.send().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use warp::Filter;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_bounds_concurrent_requests() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let (current, max) = (in_flight.clone(), max_in_flight.clone());
+        let slow = warp::path("slow").and_then(move || {
+            let (current, max) = (current.clone(), max.clone());
+            async move {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                current.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, warp::Rejection>("ok")
+            }
+        });
+        let (addr, server) = warp::serve(slow).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let permits = Semaphore::new(2);
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/slow", addr);
+        let sends = (0..6).map(|_| send_with(&permits, client.get(&url)));
+        let responses = futures::future::join_all(sends).await;
+
+        assert!(responses.iter().all(|response| response.is_ok()));
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
+
+use crate::config_helper::get_optional_config;
+use crate::http;
+
+// Mirrors the daily summary to an outgoing webhook such as Slack; failures never affect LINE
+pub async fn mirror_daily_summary(text: &str) {
+    let Some(url) = get_optional_config("integrations.slack_webhook_url").filter(|url| !url.trim().is_empty()) else {
+        return;
+    };
+
+    if let Err(e) = post_webhook(&url, text).await {
+        log::warn!("Failed to mirror the daily summary: {}", e);
+    }
+}
+
+pub async fn post_webhook(url: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let body = json!({"text": text}).to_string();
+    let response = http::send(
+        reqwest::Client::new()
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body),
+    )
+    .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("webhook returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use warp::Filter;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_post_webhook_sends_summary_text() {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let hook = warp::post()
+            .and(warp::path("hook"))
+            .and(warp::body::json())
+            .map(move |body: serde_json::Value| {
+                recorded.lock().unwrap().push(body);
+                "ok"
+            });
+        let (addr, server) = warp::serve(hook).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        post_webhook(&format!("http://{}/hook", addr), "今日摘要").await.unwrap();
+
+        assert_eq!(bodies.lock().unwrap().as_slice(), [json!({"text": "今日摘要"})]);
+    }
+}
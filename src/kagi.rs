@@ -1,78 +1,407 @@
-use crate::config_helper::{get_config, get_secret};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config_helper::{get_config, get_config_or, get_optional_config, get_secret};
+use crate::{content, http};
 use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+pub const NO_SUMMARY_FOUND: &str = "No summary found.";
+
+const SUMMARY_TYPES: [&str; 2] = ["summary", "takeaway"];
+const DEFAULT_SUMMARY_TYPE: &str = "summary";
+const DEFAULT_MAX_POLL_SECS: u64 = 30;
+const INITIAL_POLL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(5);
+
+type InFlight = Mutex<HashMap<String, Shared<BoxFuture<'static, String>>>>;
+
+static IN_FLIGHT: Lazy<InFlight> = Lazy::new(|| Mutex::new(HashMap::new()));
+
 #[derive(Debug, Serialize)]
 struct KagiSummaryRequest {
     url: String,
     engine: String,
+    summary_type: String,
     target_language: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 struct Meta {
+    #[serde(default)]
     id: String,
+    #[serde(default)]
     node: String,
+    #[serde(default)]
     ms: u64,
 }
 
+// Kagi returns `data.output`; `summary` is accepted for older response shapes
 #[derive(Debug, Deserialize, Serialize)]
 struct Data {
+    #[serde(alias = "summary")]
     output: String,
+    #[serde(default)]
     tokens: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize)]
 struct KagiSummaryResponse {
-    meta: Meta,
+    #[serde(default)]
+    meta: Option<Meta>,
     data: Data,
 }
 
 pub async fn get_kagi_summary(tldr_page_url: String) -> String {
-    let api_token = get_secret("kagi.token");
-
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-    headers.insert(AUTHORIZATION, format!("Bot {}", api_token).parse().unwrap());
-
-    let url = get_config("kagi.kagi_summarize_url");
-
     let engine = get_config("kagi.engine");
 
     let target_language = get_config("kagi.target_language");
 
+    let summary_type = resolve_summary_type(&get_config_or("kagi.summary_type", DEFAULT_SUMMARY_TYPE.to_string()));
+
     let request = KagiSummaryRequest {
         url: tldr_page_url,
         engine,
+        summary_type,
         target_language,
     };
 
+    if !get_config_or("kagi.single_flight", true) {
+        return request_summary(request).await;
+    }
+    let key = format!("{}|{}|{}|{}", request.url, request.engine, request.target_language, request.summary_type);
+    single_flight(&IN_FLIGHT, key, request_summary(request).boxed()).await
+}
+
+// Concurrent requests for the same summary share one Kagi call instead of each paying for their own
+async fn single_flight(in_flight: &InFlight, key: String, request: BoxFuture<'static, String>) -> String {
+    let shared = {
+        let mut in_flight = in_flight.lock().unwrap();
+        in_flight.entry(key.clone()).or_insert_with(|| request.shared()).clone()
+    };
+    let _entry = InFlightEntry { in_flight, key, shared: shared.clone() };
+    shared.await
+}
+
+// Removes the shared call once a caller is done with it, even when that caller is cancelled or the call panics,
+// so the next request for the key starts a fresh one
+struct InFlightEntry<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+    shared: Shared<BoxFuture<'static, String>>,
+}
+
+impl Drop for InFlightEntry<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if in_flight.get(&self.key).is_some_and(|current| current.ptr_eq(&self.shared)) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+async fn request_summary(request: KagiSummaryRequest) -> String {
+    let url = get_config("kagi.kagi_summarize_url");
+    request_summary_from(&url, &get_secret("kagi.token"), request).await
+}
+
+async fn request_summary_from(url: &str, api_token: &str, request: KagiSummaryRequest) -> String {
+    let client = reqwest::Client::new();
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(AUTHORIZATION, format!("Bot {}", api_token).parse().unwrap());
+
     let json_body = serde_json::to_string(&request).unwrap();
 
     log::info!("Kagi summary API request: {}", json_body);
 
-    let response = client
-        .post(url)
-        .headers(headers)
-        .body(json_body)
-        .send()
-        .await
-        .unwrap();
+    let response = match http::send(client.post(url).headers(headers.clone()).body(json_body)).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::warn!("Kagi summary request failed: {}", e);
+            return NO_SUMMARY_FOUND.to_string();
+        }
+    };
 
-    let response_text = response.text().await.unwrap();
+    let response_text = match response.text().await {
+        Ok(response_text) => response_text,
+        Err(e) => {
+            log::warn!("Kagi summary response could not be read: {}", e);
+            return NO_SUMMARY_FOUND.to_string();
+        }
+    };
 
     log::info!("Kagi summary API response: {}", response_text);
 
-    let response_struct: Result<KagiSummaryResponse, serde_json::Error> = serde_json::from_str(&response_text);
+    match parse_status(&response_text) {
+        KagiStatus::Complete(summary) => summary,
+        KagiStatus::Pending(id) => {
+            let Some(status_url) = get_optional_config("kagi.status_url") else {
+                log::warn!("Kagi summary {} is pending but kagi.status_url is not configured", id);
+                return NO_SUMMARY_FOUND.to_string();
+            };
+            let max_poll = Duration::from_secs(get_config_or("kagi.max_poll_secs", DEFAULT_MAX_POLL_SECS));
+            poll_summary(&client, headers, &status_url.replace("{id}", &id), max_poll, INITIAL_POLL_BACKOFF)
+                .await
+                .unwrap_or_else(|| NO_SUMMARY_FOUND.to_string())
+        }
+        KagiStatus::Failed => NO_SUMMARY_FOUND.to_string(),
+    }
+}
+
+// "takeaway" returns bullet points instead of prose
+fn resolve_summary_type(summary_type: &str) -> String {
+    let summary_type = summary_type.trim().to_lowercase();
+    if SUMMARY_TYPES.contains(&summary_type.as_str()) {
+        return summary_type;
+    }
 
-    return match response_struct {
-        Ok(_response) => {
-            let res_content = _response.data.output.clone();
-            res_content.replace("\n", "")
-        },
-        Err(_e) => {
-            "No summary found.".to_string()
+    log::warn!("Unknown kagi.summary_type {:?}, using {}", summary_type, DEFAULT_SUMMARY_TYPE);
+    DEFAULT_SUMMARY_TYPE.to_string()
+}
+
+#[derive(Debug, PartialEq)]
+enum KagiStatus {
+    Complete(String),
+    Pending(String),
+    Failed,
+}
+
+// Long pages may come back as `{"data": {"status": "pending", "id": ...}}` instead of a summary
+fn parse_status(response_text: &str) -> KagiStatus {
+    if let Some(summary) = parse_summary(response_text) {
+        return KagiStatus::Complete(summary);
+    }
+
+    let response: serde_json::Value = match serde_json::from_str(response_text) {
+        Ok(response) => response,
+        Err(_) => return KagiStatus::Failed,
+    };
+    let is_pending = response["data"]["status"]
+        .as_str()
+        .is_some_and(|status| status.eq_ignore_ascii_case("pending"));
+    let id = response["data"]["id"].as_str().or_else(|| response["meta"]["id"].as_str());
+
+    match id {
+        Some(id) if is_pending => KagiStatus::Pending(id.to_string()),
+        _ => KagiStatus::Failed,
+    }
+}
+
+async fn poll_summary(
+    client: &reqwest::Client,
+    headers: HeaderMap,
+    status_url: &str,
+    max_poll: Duration,
+    initial_backoff: Duration,
+) -> Option<String> {
+    let started = Instant::now();
+    let mut backoff = initial_backoff;
+
+    while started.elapsed() + backoff <= max_poll {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_POLL_BACKOFF);
+
+        let response_text = match http::send(client.get(status_url).headers(headers.clone())).await {
+            Ok(response) => response.text().await.ok()?,
+            Err(e) => {
+                log::warn!("Kagi status poll failed: {}", e);
+                continue;
+            }
+        };
+
+        match parse_status(&response_text) {
+            KagiStatus::Complete(summary) => return Some(summary),
+            KagiStatus::Pending(_) => continue,
+            KagiStatus::Failed => {
+                log::warn!("Kagi status poll returned an error: {}", response_text);
+                return None;
+            }
         }
     }
-}
\ No newline at end of file
+
+    log::warn!("Kagi summary still pending after {:?}", max_poll);
+    None
+}
+
+// Kagi's paragraphs and takeaway bullets keep their own lines
+fn parse_summary(response_text: &str) -> Option<String> {
+    let response_struct: KagiSummaryResponse = serde_json::from_str(response_text).ok()?;
+    Some(content::clean_summary(&response_struct.data.output))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use warp::Filter;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_captured_kagi_response() {
+        let response = r#"{"meta":{"id":"120145a1-e4ff-4c66-9fc7-bde1e1f3bc8e","node":"us-east","ms":7943},"data":{"output":"The article discusses Rust's new release.\nIt improves compile times.","tokens":11757}}"#;
+
+        assert_eq!(
+            parse_summary(response).unwrap(),
+            "The article discusses Rust's new release.\nIt improves compile times."
+        );
+    }
+
+    #[test]
+    fn test_parse_summary_cleans_whitespace() {
+        let response = r#"{"data":{"output":"Rust 2.0 ships.  \n\n\n\nCompile times drop.\n\n"}}"#;
+
+        assert_eq!(parse_summary(response).unwrap(), "Rust 2.0 ships.\n\nCompile times drop.");
+    }
+
+    #[test]
+    fn test_parse_summary_field_alias() {
+        let response = r#"{"data":{"summary":"A short summary."}}"#;
+
+        assert_eq!(parse_summary(response).unwrap(), "A short summary.");
+    }
+
+    #[test]
+    fn test_request_includes_summary_type() {
+        let request = KagiSummaryRequest {
+            url: "https://example.com".to_string(),
+            engine: "agnes".to_string(),
+            summary_type: resolve_summary_type("Takeaway"),
+            target_language: "EN".to_string(),
+        };
+
+        let body: serde_json::Value = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(body["summary_type"], "takeaway");
+        assert_eq!(resolve_summary_type("bullets"), "summary");
+    }
+
+    #[test]
+    fn test_parse_takeaway_keeps_bullets() {
+        let response = r#"{"data":{"output":"- Rust 2.0 ships.\n- Compile times drop.","tokens":42}}"#;
+
+        assert_eq!(parse_summary(response).unwrap(), "- Rust 2.0 ships.\n- Compile times drop.");
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            parse_status(r#"{"data":{"output":"Done."}}"#),
+            KagiStatus::Complete("Done.".to_string())
+        );
+        assert_eq!(
+            parse_status(r#"{"meta":{"id":"req-1"},"data":{"status":"pending","id":"sum-1"}}"#),
+            KagiStatus::Pending("sum-1".to_string())
+        );
+        assert_eq!(parse_status("<html>Bad gateway</html>"), KagiStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_poll_summary_after_pending_responses() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        let status = warp::path!("status" / String).map(move |id: String| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                format!(r#"{{"data":{{"status":"pending","id":"{}"}}}}"#, id)
+            } else {
+                r#"{"data":{"output":"Polled summary.","tokens":42}}"#.to_string()
+            }
+        });
+        let (addr, server) = warp::serve(status).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let summary = poll_summary(
+            &reqwest::Client::new(),
+            HeaderMap::new(),
+            &format!("http://{}/status/sum-1", addr),
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert_eq!(summary.unwrap(), "Polled summary.");
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_poll_summary_gives_up() {
+        let status = warp::path!("status").map(|| r#"{"data":{"status":"pending","id":"sum-1"}}"#);
+        let (addr, server) = warp::serve(status).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let summary = poll_summary(
+            &reqwest::Client::new(),
+            HeaderMap::new(),
+            &format!("http://{}/status", addr),
+            Duration::from_millis(100),
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert!(summary.is_none());
+    }
+
+    #[test]
+    fn test_parse_error_response() {
+        let response = r#"{"meta":{"id":"abc","node":"us-east","ms":10},"data":null,"error":[{"code":1,"msg":"Unauthorized"}]}"#;
+
+        assert!(parse_summary(response).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_share_one_call() {
+        let in_flight = InFlight::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let request = |calls: Arc<AtomicUsize>| {
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                "Shared summary.".to_string()
+            }
+            .boxed()
+        };
+        let key = "https://example.com|agnes|EN|summary".to_string();
+
+        let (first, second) = tokio::join!(
+            single_flight(&in_flight, key.clone(), request(calls.clone())),
+            single_flight(&in_flight, key.clone(), request(calls.clone())),
+        );
+
+        assert_eq!(first, "Shared summary.");
+        assert_eq!(second, "Shared summary.");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_shared_request_is_not_reused() {
+        let in_flight: &'static InFlight = Box::leak(Box::default());
+        let key = "https://example.com|agnes|EN|summary".to_string();
+        let request = || KagiSummaryRequest {
+            url: "https://example.com".to_string(),
+            engine: "agnes".to_string(),
+            summary_type: "summary".to_string(),
+            target_language: "EN".to_string(),
+        };
+
+        // A network error ends the shared call with the no-summary text instead of a panic
+        let unreachable = request_summary_from("http://127.0.0.1:9/summarize", "token", request()).boxed();
+        assert_eq!(single_flight(in_flight, key.clone(), unreachable).await, NO_SUMMARY_FOUND);
+        assert!(in_flight.lock().unwrap().is_empty());
+
+        // A leader that panics still leaves no stale entry behind
+        let panicking = async { panic!("Kagi call failed") }.boxed();
+        assert!(tokio::spawn(single_flight(in_flight, key.clone(), panicking)).await.is_err());
+        assert!(in_flight.lock().unwrap().is_empty());
+
+        let retried = single_flight(in_flight, key, async { "A fresh summary.".to_string() }.boxed()).await;
+        assert_eq!(retried, "A fresh summary.");
+    }
+}
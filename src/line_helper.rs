@@ -1,18 +1,61 @@
-use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::general_purpose::{STANDARD as BASE64, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD};
 use base64::Engine;
 use bytes::Bytes;
 use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sha2::Sha256;
 use std::error::Error;
 
-use crate::config_helper::{get_config, get_secret};
+use crate::config_helper::{get_optional_config, get_secret};
+use crate::readrss::Story;
+use crate::{content, redact};
 
-#[derive(Serialize, Deserialize)]
+const MAX_SENDER_NAME_CHARS: usize = 20;
+pub const MAX_BUTTONS_ACTIONS: usize = 4;
+const MAX_BUTTONS_TEXT_CHARS: usize = 160;
+const MAX_ALT_TEXT_CHARS: usize = 400;
+const MAX_ACTION_LABEL_CHARS: usize = 20;
+const BULLET_MARKERS: [&str; 4] = ["- ", "* ", "•", "・"];
+
+pub const MAX_TEXT_MESSAGE_CHARS: usize = 5000;
+pub const MAX_MESSAGES_PER_REQUEST: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LineMessage {
     #[serde(rename = "type")]
     pub message_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+    #[serde(rename = "altText", default, skip_serializing_if = "Option::is_none")]
+    pub alt_text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<LineTemplate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<LineSender>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LineTemplate {
+    #[serde(rename = "type")]
+    pub template_type: String,
     pub text: String,
+    pub actions: Vec<LineAction>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LineAction {
+    #[serde(rename = "type")]
+    pub action_type: String,
+    pub label: String,
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LineSender {
+    pub name: String,
+    #[serde(rename = "iconUrl", skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,18 +63,43 @@ pub struct LineBroadcastRequest {
     pub messages: Vec<LineMessage>,
 }
 
+// Either or both of recipient (audiences) and filter (demographics) narrow who receives the messages
+#[derive(Serialize, Deserialize)]
+pub struct LineNarrowcastRequest {
+    pub messages: Vec<LineMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LineNarrowcastProgress {
+    // "waiting", "sending", "succeeded" or "failed"
+    pub phase: String,
+    pub success_count: Option<u64>,
+    pub failure_count: Option<u64>,
+    pub target_count: Option<u64>,
+    pub error_code: Option<u32>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LineMessageRequest {
-    pub replyToken: String,
+    #[serde(rename = "replyToken")]
+    pub reply_token: String,
     pub messages: Vec<LineMessage>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LineErrorResponse {
     pub message: String,
+    #[allow(dead_code)]
+    #[serde(default)]
     pub details: Vec<LineErrorDetail>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 pub struct LineErrorDetail {
     pub message: String,
@@ -44,10 +112,261 @@ pub struct LineSendMessageRequest {
     pub messages: Vec<LineMessage>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct LineLoadingRequest {
+    #[serde(rename = "chatId")]
+    pub chat_id: String,
+    #[serde(rename = "loadingSeconds")]
+    pub loading_seconds: u32,
+}
+
+pub fn create_sender(name: Option<String>, icon_url: Option<String>) -> Option<LineSender> {
+    let name = name?.trim().to_string();
+    if name.is_empty() {
+        return None;
+    }
+
+    // LINE rejects sender names longer than 20 characters
+    Some(LineSender {
+        name: name.chars().take(MAX_SENDER_NAME_CHARS).collect(),
+        icon_url: icon_url.filter(|url| !url.trim().is_empty()),
+    })
+}
+
+pub fn get_configured_sender() -> Option<LineSender> {
+    create_sender(
+        get_optional_config("line.sender.name"),
+        get_optional_config("line.sender.icon_url"),
+    )
+}
+
+// A buttons template holds at most four URI actions, so stories are spread over several messages
+pub fn create_buttons_template(stories: &[Story], sender: Option<LineSender>) -> Vec<LineMessage> {
+    stories
+        .chunks(MAX_BUTTONS_ACTIONS)
+        .enumerate()
+        .map(|(chunk_index, chunk)| {
+            let first_rank = chunk_index * MAX_BUTTONS_ACTIONS + 1;
+            let titles: Vec<String> = chunk
+                .iter()
+                .enumerate()
+                .map(|(i, story)| format!("{}. {}", first_rank + i, story.story))
+                .collect();
+            let actions = chunk
+                .iter()
+                .zip(&titles)
+                .map(|(story, title)| LineAction {
+                    action_type: "uri".to_string(),
+                    label: title.chars().take(MAX_ACTION_LABEL_CHARS).collect(),
+                    uri: story.storylink.to_string(),
+                })
+                .collect();
+
+            let text = titles.join("\n");
+            LineMessage {
+                message_type: "template".to_string(),
+                text: String::new(),
+                alt_text: Some(content::truncate_chars(&text, MAX_ALT_TEXT_CHARS)),
+                template: Some(LineTemplate {
+                    template_type: "buttons".to_string(),
+                    text: text.chars().take(MAX_BUTTONS_TEXT_CHARS).collect(),
+                    actions,
+                }),
+                sender: sender.clone(),
+            }
+        })
+        .collect()
+}
+
+// LINE rejects text messages over the limit, so long ones are split at line breaks
+pub fn ensure_text_size(message: LineMessage) -> Vec<LineMessage> {
+    if message.text.chars().count() <= MAX_TEXT_MESSAGE_CHARS {
+        return vec![message];
+    }
+
+    split_text(&message.text, MAX_TEXT_MESSAGE_CHARS)
+        .into_iter()
+        .map(|text| LineMessage {
+            text,
+            ..message.clone()
+        })
+        .collect()
+}
+
+fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+
+    for line in text.split_inclusive('\n') {
+        let line_chars = line.chars().count();
+        if current_chars + line_chars > max_chars && !current.is_empty() {
+            parts.push(current.trim_end().to_string());
+            current.clear();
+            current_chars = 0;
+        }
+
+        // A single line longer than the limit is cut wherever it has to be
+        let mut rest = line;
+        while rest.chars().count() > max_chars {
+            let (byte_index, _) = rest.char_indices().nth(max_chars).unwrap();
+            parts.push(rest[..byte_index].to_string());
+            rest = &rest[byte_index..];
+        }
+        current.push_str(rest);
+        current_chars += rest.chars().count();
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim_end().to_string());
+    }
+    parts
+}
+
+// The summary body is trimmed so the footer always fits in one text message
+pub fn append_summary_footer(summary: &str, footer: Option<String>, footer_url: Option<String>) -> String {
+    let footer = footer.filter(|footer| !footer.trim().is_empty());
+    let Some(footer) = footer else {
+        return summary.to_string();
+    };
+
+    let footer = match footer_url.filter(|url| !url.trim().is_empty()) {
+        Some(url) => format!("\n\n{}\n{}", footer, url),
+        None => format!("\n\n{}", footer),
+    };
+    let body_chars = MAX_TEXT_MESSAGE_CHARS.saturating_sub(footer.chars().count());
+    format!("{}{}", content::truncate_chars(summary, body_chars), footer)
+}
+
+// Bullet style puts every point on its own "• " line, whatever marker the model used;
+// blank lines between sections are kept
+pub fn format_bullets(summary: &str) -> String {
+    summary
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            match BULLET_MARKERS.iter().find_map(|marker| line.strip_prefix(marker)) {
+                Some(point) => format!("• {}", point.trim()),
+                None => line.to_string(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[derive(Deserialize)]
+struct GroupedSummary {
+    groups: Vec<SummaryGroup>,
+}
+
+#[derive(Deserialize)]
+struct SummaryGroup {
+    topic: String,
+    items: Vec<SummaryItem>,
+}
+
+// prompt.summary_grouped_sentiment rates each item, prompt.summary_grouped leaves items as plain strings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SummaryItem {
+    Plain(String),
+    Rated {
+        text: String,
+        #[serde(default)]
+        sentiment: Option<String>,
+    },
+}
+
+impl SummaryItem {
+    fn render(&self, sentiment_icons: bool) -> String {
+        match self {
+            SummaryItem::Rated { text, sentiment: Some(sentiment) } if sentiment_icons => match sentiment_emoji(sentiment) {
+                Some(emoji) => format!("• {} {}", emoji, text.trim()),
+                None => format!("• {}", text.trim()),
+            },
+            SummaryItem::Plain(text) | SummaryItem::Rated { text, .. } => format!("• {}", text.trim()),
+        }
+    }
+}
+
+// Unknown sentiments get no icon rather than a guess
+fn sentiment_emoji(sentiment: &str) -> Option<&'static str> {
+    match sentiment.trim().to_lowercase().as_str() {
+        "positive" => Some("📈"),
+        "concerning" => Some("⚠️"),
+        "debatable" => Some("🤔"),
+        _ => None,
+    }
+}
+
+// prompt.summary_grouped answers with {"groups": [{"topic": ..., "items": [...]}]}; None when it
+// isn't that shape, so the caller can send the text as it is
+pub fn format_grouped_summary(summary: &str, sentiment_icons: bool) -> Option<String> {
+    let json = summary
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```");
+    let grouped: GroupedSummary = serde_json::from_str(json.trim()).ok()?;
+
+    let sections: Vec<String> = grouped
+        .groups
+        .iter()
+        .filter(|group| !group.items.is_empty())
+        .map(|group| {
+            let items: Vec<String> = group.items.iter().map(|item| item.render(sentiment_icons)).collect();
+            format!("【{}】\n{}", group.topic.trim(), items.join("\n"))
+        })
+        .collect();
+    (!sections.is_empty()).then(|| sections.join("\n\n"))
+}
+
+// Sections past max_messages stay together in the last message so nothing is dropped
+pub fn create_summary_section_messages(summary: &str, max_messages: usize, sender: Option<LineSender>) -> Vec<LineMessage> {
+    let sections: Vec<&str> = summary
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|section| !section.is_empty())
+        .collect();
+    let split_at = sections.len().min(max_messages.max(1)).saturating_sub(1);
+    let (head, tail) = sections.split_at(split_at);
+
+    head.iter()
+        .map(|section| section.to_string())
+        .chain((!tail.is_empty()).then(|| tail.join("\n\n")))
+        .map(|text| LineMessage {
+            message_type: "text".to_string(),
+            text,
+            alt_text: None,
+            template: None,
+            sender: sender.clone(),
+        })
+        .collect()
+}
+
+pub fn create_help_message(intro: &str, capabilities: &[String], sender: Option<LineSender>) -> LineMessage {
+    let lines = capabilities.iter().map(|capability| format!("• {}", capability)).collect::<Vec<String>>();
+    LineMessage {
+        message_type: "text".to_string(),
+        text: format!("{}\n{}", intro, lines.join("\n")),
+        alt_text: None,
+        template: None,
+        sender,
+    }
+}
+
+pub fn get_configured_summary_footer(summary: &str) -> String {
+    append_summary_footer(
+        summary,
+        get_optional_config("line.summary_footer"),
+        get_optional_config("line.summary_footer_url"),
+    )
+}
+
+#[allow(dead_code)]
 pub fn generate_signature(channel_secret: &str, body: &[u8]) -> String {
     let mut hmac_sha256 =
         Hmac::<Sha256>::new_from_slice(channel_secret.as_bytes()).expect("Failed to create HMAC");
-    hmac_sha256.update(&body);
+    hmac_sha256.update(body);
 
     BASE64.encode(hmac_sha256.finalize().into_bytes())
 }
@@ -55,20 +374,228 @@ pub fn generate_signature(channel_secret: &str, body: &[u8]) -> String {
 pub fn is_signature_valid(x_line_signature: String, body: &Bytes) -> Result<(), Box<dyn Error>> {
     let channel_secret = get_secret("channel.secret");
 
-    log::info!("channel secret: {}", channel_secret);
+    log::info!("body content: {}", redact::redact(&String::from_utf8_lossy(body)));
+
+    verify_signature(&channel_secret, &x_line_signature, body)
+}
+
+// LINE sends padded standard base64, but proxies may rewrite it as URL-safe or strip the padding
+fn decode_signature(x_line_signature: &str) -> Option<Vec<u8>> {
+    let signature = x_line_signature.trim();
+
+    [BASE64, URL_SAFE, STANDARD_NO_PAD, URL_SAFE_NO_PAD]
+        .iter()
+        .find_map(|engine| engine.decode(signature).ok())
+}
+
+fn verify_signature(channel_secret: &str, x_line_signature: &str, body: &[u8]) -> Result<(), Box<dyn Error>> {
+    let signature = decode_signature(x_line_signature).ok_or("Invalid signature encoding")?;
+
+    let mut hmac_sha256 =
+        Hmac::<Sha256>::new_from_slice(channel_secret.as_bytes()).expect("Failed to create HMAC");
+    hmac_sha256.update(body);
+    hmac_sha256
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid signature".into())
+}
 
-    let encoded_body = generate_signature(&channel_secret, &body);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    log::info!("encoded body: {}", encoded_body);
-    log::info!("x-line-signature: {:?}", x_line_signature);
-    log::info!(
-        "body content: {}",
-        String::from_utf8(body.to_vec()).unwrap()
-    );
+    #[test]
+    fn test_create_help_message_lists_capabilities() {
+        let capabilities = vec![
+            "latest stories: list today's Hacker News stories".to_string(),
+            "summarize #N: summarize story number N".to_string(),
+        ];
 
-    if encoded_body != x_line_signature {
-        return Err("Invalid signature".into());
+        let message = create_help_message("我可以幫你：", &capabilities, None);
+
+        assert_eq!(message.message_type, "text");
+        assert_eq!(
+            message.text,
+            "我可以幫你：\n• latest stories: list today's Hacker News stories\n• summarize #N: summarize story number N"
+        );
+    }
+
+    #[test]
+    fn test_sender_is_serialized_when_configured() {
+        let message = LineMessage {
+            message_type: "text".to_string(),
+            text: "Hello".to_string(),
+            alt_text: None,
+            template: None,
+            sender: create_sender(
+                Some("HN Daily".to_string()),
+                Some("https://example.com/icon.png".to_string()),
+            ),
+        };
+
+        let json: serde_json::Value = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(json["sender"]["name"], "HN Daily");
+        assert_eq!(json["sender"]["iconUrl"], "https://example.com/icon.png");
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn test_sender_is_omitted_when_not_configured() {
+        let message = LineMessage {
+            message_type: "text".to_string(),
+            text: "Hello".to_string(),
+            alt_text: None,
+            template: None,
+            sender: create_sender(None, Some("https://example.com/icon.png".to_string())),
+        };
+
+        let json: serde_json::Value = serde_json::to_value(&message).unwrap();
+
+        assert!(json.get("sender").is_none());
+    }
+
+    #[test]
+    fn test_sender_name_is_truncated() {
+        let sender = create_sender(Some("Hacker News Daily Digest Bot".to_string()), None).unwrap();
+
+        assert_eq!(sender.name.chars().count(), 20);
+        assert!(sender.icon_url.is_none());
+    }
+
+    #[test]
+    fn test_oversized_text_message_is_split() {
+        let paragraph = format!("{}\n", "a".repeat(1999));
+        let message = LineMessage {
+            message_type: "text".to_string(),
+            text: paragraph.repeat(4),
+            alt_text: None,
+            template: None,
+            sender: create_sender(Some("HN Daily".to_string()), None),
+        };
+
+        let messages = ensure_text_size(message);
+
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.text.chars().count() <= MAX_TEXT_MESSAGE_CHARS));
+        assert!(messages.iter().all(|m| m.sender.is_some()));
+        assert_eq!(messages[0].text.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_split_text_without_line_breaks() {
+        let parts = split_text(&"字".repeat(12), 5);
+
+        assert_eq!(parts, vec!["字".repeat(5), "字".repeat(5), "字".repeat(2)]);
+    }
+
+    #[test]
+    fn test_summary_footer_is_appended() {
+        let summary = append_summary_footer(
+            "Today's summary.",
+            Some("Generated by AI • Source: Hacker News".to_string()),
+            Some("https://example.com/unsubscribe".to_string()),
+        );
+
+        assert_eq!(
+            summary,
+            "Today's summary.\n\nGenerated by AI • Source: Hacker News\nhttps://example.com/unsubscribe"
+        );
+        assert_eq!(append_summary_footer("Today's summary.", None, None), "Today's summary.");
+    }
+
+    #[test]
+    fn test_summary_footer_survives_truncation() {
+        let long_summary = "a".repeat(MAX_TEXT_MESSAGE_CHARS);
+
+        let summary = append_summary_footer(&long_summary, Some("Footer".to_string()), None);
+
+        assert_eq!(summary.chars().count(), MAX_TEXT_MESSAGE_CHARS);
+        assert!(summary.ends_with("\n\nFooter"));
+    }
+
+    #[test]
+    fn test_format_bullets() {
+        let summary = "今日重點：\n- Rust 2.0 發布\n* 新的排程器\n\n•SQLite 新版本\n・LLM 評測";
+
+        assert_eq!(
+            format_bullets(summary),
+            "今日重點：\n• Rust 2.0 發布\n• 新的排程器\n\n• SQLite 新版本\n• LLM 評測"
+        );
+    }
+
+    #[test]
+    fn test_grouped_summary_renders_topic_headers() {
+        let response = r#"```json
+{"groups": [
+    {"topic": "AI", "items": ["新的開源模型發布", "LLM 評測方法"]},
+    {"topic": "Security", "items": ["OpenSSH 漏洞修補"]},
+    {"topic": "Empty", "items": []}
+]}
+```"#;
+
+        assert_eq!(
+            format_grouped_summary(response, true).unwrap(),
+            "【AI】\n• 新的開源模型發布\n• LLM 評測方法\n\n【Security】\n• OpenSSH 漏洞修補"
+        );
+        assert_eq!(format_grouped_summary("今天的新聞重點如下……", false), None);
+        assert_eq!(format_grouped_summary(r#"{"groups": []}"#, false), None);
+    }
+
+    #[test]
+    fn test_sentiment_maps_to_emoji() {
+        assert_eq!(sentiment_emoji("positive"), Some("📈"));
+        assert_eq!(sentiment_emoji("Concerning"), Some("⚠️"));
+        assert_eq!(sentiment_emoji("debatable"), Some("🤔"));
+        assert_eq!(sentiment_emoji("neutral"), None);
+
+        let response = r#"{"groups": [{"topic": "AI", "items": [
+            {"text": "新的開源模型發布", "sentiment": "positive"},
+            {"text": "模型訓練資料爭議", "sentiment": "debatable"},
+            {"text": "沒有評價的項目"}
+        ]}]}"#;
+        assert_eq!(
+            format_grouped_summary(response, true).unwrap(),
+            "【AI】\n• 📈 新的開源模型發布\n• 🤔 模型訓練資料爭議\n• 沒有評價的項目"
+        );
+        assert_eq!(
+            format_grouped_summary(response, false).unwrap(),
+            "【AI】\n• 新的開源模型發布\n• 模型訓練資料爭議\n• 沒有評價的項目"
+        );
+    }
+
+    #[test]
+    fn test_summary_sections_become_messages() {
+        let summary = "【AI】\n• 新模型\n\n【Security】\n• 漏洞修補\n\n\n【Programming】\n• Rust 2.0";
+
+        let messages = create_summary_section_messages(summary, MAX_MESSAGES_PER_REQUEST, None);
+        let texts: Vec<&str> = messages.iter().map(|message| message.text.as_str()).collect();
+        assert_eq!(texts, vec!["【AI】\n• 新模型", "【Security】\n• 漏洞修補", "【Programming】\n• Rust 2.0"]);
+
+        let messages = create_summary_section_messages(summary, 2, None);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].text, "【Security】\n• 漏洞修補\n\n【Programming】\n• Rust 2.0");
+    }
+
+    #[test]
+    fn test_verify_padded_signature() {
+        let body = br#"{"events":[]}"#;
+        let signature = generate_signature("secret", body);
+
+        assert!(signature.ends_with('='));
+        assert!(verify_signature("secret", &signature, body).is_ok());
+        assert!(verify_signature("secret", &format!(" {}\n", signature), body).is_ok());
+        assert!(verify_signature("other-secret", &signature, body).is_err());
+    }
+
+    #[test]
+    fn test_verify_url_safe_and_unpadded_signatures() {
+        let body = br#"{"events":[]}"#;
+        let mut hmac_sha256 = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        hmac_sha256.update(body);
+        let digest = hmac_sha256.finalize().into_bytes();
+
+        assert!(verify_signature("secret", &URL_SAFE.encode(digest), body).is_ok());
+        assert!(verify_signature("secret", &URL_SAFE_NO_PAD.encode(digest), body).is_ok());
+        assert!(verify_signature("secret", &STANDARD_NO_PAD.encode(digest), body).is_ok());
+        assert!(verify_signature("secret", "not base64!", body).is_err());
+    }
+}
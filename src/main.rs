@@ -1,62 +1,183 @@
-use serde_json::json;
-use warp::http::Error;
-use warp::reply::Json;
+use std::io::Write;
+
 use warp::Filter;
 
+mod budget;
 mod chatgpt;
+mod clock;
+mod commands;
 mod config_helper;
+mod content;
+mod export;
+mod feed;
 mod kagi;
 mod line_helper;
+mod media;
+mod metrics;
 mod handler;
+mod http;
+mod integrations;
+mod quota;
+mod rate_limit;
 mod readrss;
+mod redact;
 mod request_handler;
+mod request_id;
+mod schedule;
+mod stories;
+mod summary;
+mod webhook_queue;
 
 #[tokio::main]
 async fn main() {
-    // Initialize logger
-    env_logger::init();
+    // Initialize logger, tagging lines logged while handling a request with its ID
+    env_logger::Builder::from_default_env()
+        .format(|buf, record| {
+            let style = buf.default_level_style(record.level());
+            let request_id = request_id::current().map(|id| format!(" {}", id)).unwrap_or_default();
+            writeln!(
+                buf,
+                "[{} {style}{}{style:#} {}{}] {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                request_id,
+                record.args()
+            )
+        })
+        .init();
+    handler::mark_started();
+
+    let config_status = config_helper::report_status();
+    if config_status.is_ready() {
+        log::info!("Configuration ready: {}", config_status);
+    } else {
+        log::warn!("Configuration incomplete: {}", config_status);
+    }
+
+    if let Err(e) = feed::StorySelector::from_config() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = export::check_config() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    if config_helper::get_config_or("rss.prefetch_on_start", false) {
+        tokio::spawn(readrss::prefetch());
+    }
+    tokio::spawn(handler::run_summary_warmup());
+
+    let routes = build_routes().with(warp::log("daily_hacker_news_bot"));
 
+    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+}
+
+// Routes disabled under [routes] are left out and answer 404
+fn build_routes() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let parse_request_route = warp::post()
         .and(warp::path("webhook"))
+        .and(handler::route_enabled("webhook"))
         .and(warp::header::<String>("x-line-signature"))
+        .and(request_id::request_id())
         .and(warp::body::bytes())
         .and_then(handler::parse_request_handler);
 
     let test_route = warp::get()
         .and(warp::path("hello"))
-        .map(|| Ok::<Json, Error>(warp::reply::json(&json!({"success": true}))));
+        .and(handler::route_enabled("hello"))
+        .and_then(handler::hello_handler);
 
     let latest_title_route = warp::get()
         .and(warp::path("getLatestTitle"))
+        .and(handler::route_enabled("get_latest_title"))
         .and_then(handler::get_latest_title);
 
     let get_stories_route = warp::get()
         .and(warp::path("getLatestStories"))
+        .and(handler::route_enabled("get_latest_stories"))
         .and_then(handler::get_latest_stories);
 
     let send_line_broadcast_route = warp::get()
         .and(warp::path("sendTodayStories"))
+        .and(handler::route_enabled("send_today_stories"))
         .and_then(handler::send_line_broadcast);
 
     let broadcast_daily_summary_route = warp::get()
         .and(warp::path("broadcastDailySummary"))
+        .and(handler::route_enabled("broadcast_daily_summary"))
+        .and(handler::summary_query())
         .and_then(handler::broadcast_daily_summary);
 
+    let narrowcast_daily_summary_route = warp::post()
+        .and(warp::path("narrowcastDailySummary"))
+        .and(handler::route_enabled("narrowcast_daily_summary"))
+        .and(warp::body::json())
+        .and_then(handler::narrowcast_daily_summary);
+
+    let narrowcast_progress_route = warp::get()
+        .and(warp::path("narrowcastProgress"))
+        .and(handler::route_enabled("narrowcast_progress"))
+        .and(warp::query::<handler::NarrowcastProgressQuery>())
+        .and_then(handler::narrowcast_progress);
+
+    let summarize_story_route = warp::get()
+        .and(warp::path("summarizeStory"))
+        .and(handler::route_enabled("summarize_story"))
+        .and(handler::story_summary_query())
+        .and_then(handler::preview_story_summary);
+
+    let broadcast_digest_route = warp::get()
+        .and(warp::path("broadcastDigest"))
+        .and(handler::route_enabled("broadcast_digest"))
+        .and(handler::summary_query())
+        .and_then(handler::broadcast_digest);
+
+    let preview_daily_summary_route = warp::get()
+        .and(warp::path("previewDailySummary"))
+        .and(handler::route_enabled("preview_daily_summary"))
+        .and(handler::summary_query())
+        .and_then(handler::preview_daily_summary);
+
     let conversation_route = warp::post()
         .and(warp::path("conversation"))
+        .and(handler::route_enabled("conversation"))
+        .and(request_id::request_id())
         .and(warp::body::bytes())
         .and_then(handler::conversation_handler);
 
-    let log_filter = warp::log("daily_hacker_news_bot");
+    let test_prompt_route = warp::post()
+        .and(warp::path!("admin" / "testPrompt"))
+        .and(handler::route_enabled("test_prompt"))
+        .and(warp::body::json())
+        .and_then(handler::test_prompt_handler);
+
+    let metrics_route = warp::get()
+        .and(warp::path("metrics"))
+        .and(handler::route_enabled("metrics"))
+        .and_then(handler::metrics_handler);
 
-    let routes = parse_request_route
+    let exports_route = warp::get()
+        .and(warp::path("exports"))
+        .and(handler::route_enabled("exports"))
+        .and(handler::exports_enabled())
+        .and(warp::fs::dir(export::dir()));
+
+    parse_request_route
         .or(test_route)
         .or(latest_title_route)
         .or(get_stories_route)
         .or(send_line_broadcast_route)
         .or(broadcast_daily_summary_route)
+        .or(broadcast_digest_route)
+        .or(narrowcast_daily_summary_route)
+        .or(narrowcast_progress_route)
+        .or(summarize_story_route)
+        .or(preview_daily_summary_route)
         .or(conversation_route)
-        .with(log_filter);
-
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await;
+        .or(test_prompt_route)
+        .or(metrics_route)
+        .or(exports_route)
 }
+
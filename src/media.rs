@@ -0,0 +1,56 @@
+use crate::stories;
+
+const VIDEO_HOSTS: [&str; 4] = ["youtube.com", "youtu.be", "vimeo.com", "twitch.tv"];
+const VIDEO_EXTENSIONS: [&str; 3] = [".mp4", ".webm", ".mov"];
+const IMAGE_EXTENSIONS: [&str; 5] = [".png", ".jpg", ".jpeg", ".gif", ".webp"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Article,
+    Video,
+    Image,
+}
+
+impl LinkKind {
+    // Shown before the title in story lists, articles keep the plain title
+    pub fn marker(self) -> Option<&'static str> {
+        match self {
+            LinkKind::Article => None,
+            LinkKind::Video => Some("▶️"),
+            LinkKind::Image => Some("🖼️"),
+        }
+    }
+}
+
+pub fn classify_link(url: &str) -> LinkKind {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return LinkKind::Article;
+    };
+    let path = parsed.path().to_lowercase();
+    let is_video_host = stories::domain_of(url)
+        .is_some_and(|domain| VIDEO_HOSTS.iter().any(|host| domain == *host || domain.ends_with(&format!(".{}", host))));
+
+    if is_video_host || VIDEO_EXTENSIONS.iter().any(|extension| path.ends_with(extension)) {
+        LinkKind::Video
+    } else if IMAGE_EXTENSIONS.iter().any(|extension| path.ends_with(extension)) {
+        LinkKind::Image
+    } else {
+        LinkKind::Article
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_link() {
+        assert_eq!(classify_link("https://www.youtube.com/watch?v=dQw4w9WgXcQ"), LinkKind::Video);
+        assert_eq!(classify_link("https://youtu.be/dQw4w9WgXcQ"), LinkKind::Video);
+        assert_eq!(classify_link("https://player.vimeo.com/video/1"), LinkKind::Video);
+        assert_eq!(classify_link("https://example.com/demo.MP4"), LinkKind::Video);
+        assert_eq!(classify_link("https://i.imgur.com/chart.png"), LinkKind::Image);
+        assert_eq!(classify_link("https://example.com/posts/youtube.com-is-down"), LinkKind::Article);
+        assert_eq!(classify_link("not a url"), LinkKind::Article);
+    }
+}
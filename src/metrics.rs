@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+pub const RSS_PARSE_EMPTY_TOTAL: &str = "hnbot_rss_parse_empty_total";
+pub const UNKNOWN_FUNCTION_CALL_TOTAL: &str = "hnbot_unknown_function_call_total";
+pub const WEBHOOK_SHED_TOTAL: &str = "hnbot_webhook_shed_total";
+pub const LINE_MONTHLY_MESSAGES: &str = "hnbot_line_monthly_messages";
+pub const LINE_MONTHLY_QUOTA: &str = "hnbot_line_monthly_quota";
+pub const SUMMARY_IN_FLIGHT: &str = "hnbot_summary_in_flight";
+pub const SUMMARY_MAX_IN_FLIGHT: &str = "hnbot_summary_max_in_flight";
+pub const SUMMARY_AVG_LATENCY_MS: &str = "hnbot_summary_avg_latency_ms";
+pub const SUMMARY_LATENCY_MS_TOTAL: &str = "hnbot_summary_latency_ms_total";
+pub const SUMMARIES_TOTAL: &str = "hnbot_summaries_total";
+pub const CHATGPT_TOKENS_TOTAL: &str = "hnbot_chatgpt_tokens_total";
+pub const CHATGPT_DAILY_TOKENS: &str = "hnbot_chatgpt_daily_tokens";
+
+static COUNTERS: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+static GAUGES: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn increment(name: &'static str) {
+    increment_by(name, 1);
+}
+
+pub fn increment_by(name: &'static str, value: u64) {
+    *COUNTERS.lock().unwrap().entry(name).or_insert(0) += value;
+}
+
+pub fn set_gauge(name: &'static str, value: u64) {
+    GAUGES.lock().unwrap().insert(name, value);
+}
+
+// Returns the new value so callers can track a high-water mark
+pub fn adjust_gauge(name: &'static str, delta: i64) -> u64 {
+    let mut gauges = GAUGES.lock().unwrap();
+    let value = gauges.entry(name).or_insert(0);
+    *value = value.saturating_add_signed(delta);
+    *value
+}
+
+pub fn raise_gauge(name: &'static str, value: u64) {
+    let mut gauges = GAUGES.lock().unwrap();
+    let current = gauges.entry(name).or_insert(0);
+    *current = (*current).max(value);
+}
+
+pub fn get(name: &str) -> u64 {
+    COUNTERS.lock().unwrap().get(name).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+pub fn get_gauge(name: &str) -> u64 {
+    GAUGES.lock().unwrap().get(name).copied().unwrap_or(0)
+}
+
+// Prometheus text exposition format
+pub fn render() -> String {
+    let counters = COUNTERS.lock().unwrap().clone();
+    let gauges = GAUGES.lock().unwrap().clone();
+
+    counters
+        .iter()
+        .map(|(name, value)| (name, value, "counter"))
+        .chain(gauges.iter().map(|(name, value)| (name, value, "gauge")))
+        .map(|(name, value, metric_type)| format!("# TYPE {} {}\n{} {}\n", name, metric_type, name, value))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        increment("hnbot_test_render_total");
+        increment("hnbot_test_render_total");
+
+        assert_eq!(get("hnbot_test_render_total"), 2);
+        assert!(render().contains("# TYPE hnbot_test_render_total counter\nhnbot_test_render_total 2\n"));
+
+        set_gauge("hnbot_test_render_gauge", 7);
+        assert!(render().contains("# TYPE hnbot_test_render_gauge gauge\nhnbot_test_render_gauge 7\n"));
+    }
+
+    #[test]
+    fn test_adjust_and_raise_gauge() {
+        assert_eq!(adjust_gauge("hnbot_test_adjust_gauge", 2), 2);
+        assert_eq!(adjust_gauge("hnbot_test_adjust_gauge", -1), 1);
+        assert_eq!(adjust_gauge("hnbot_test_adjust_gauge", -5), 0);
+
+        raise_gauge("hnbot_test_raise_gauge", 3);
+        raise_gauge("hnbot_test_raise_gauge", 1);
+        assert_eq!(get_gauge("hnbot_test_raise_gauge"), 3);
+    }
+}
@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use reqwest::header::AUTHORIZATION;
+use serde::Deserialize;
+
+use crate::config_helper::{get_config_or, get_secret};
+use crate::{http, metrics};
+
+const DEFAULT_CONSUMPTION_URL: &str = "https://api.line.me/v2/bot/message/quota/consumption";
+const DEFAULT_WARNING_RATIO: f64 = 0.9;
+
+// LINE bills broadcasts per recipient, so the month's usage is read from LINE rather than counted here
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuotaConsumption {
+    total_usage: u64,
+}
+
+// Last usage LINE reported, used when it can't be reached
+static LAST_USAGE: Lazy<Mutex<Option<u64>>> = Lazy::new(|| Mutex::new(None));
+
+// 0 means the plan has no cap
+fn monthly_quota() -> Option<u64> {
+    Some(get_config_or("line.monthly_quota", 0u64)).filter(|quota| *quota > 0)
+}
+
+fn warning_ratio() -> f64 {
+    get_config_or("line.quota_warning_ratio", DEFAULT_WARNING_RATIO)
+}
+
+fn is_near_quota(messages: u64, quota: u64, warning_ratio: f64) -> bool {
+    messages as f64 >= quota as f64 * warning_ratio
+}
+
+pub fn is_tracked() -> bool {
+    monthly_quota().is_some()
+}
+
+async fn fetch_consumption(token: &str, url: &str) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let response = http::send(reqwest::Client::new().get(url).header(AUTHORIZATION, format!("Bearer {}", token)))
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let consumption: QuotaConsumption = serde_json::from_str(&response)?;
+    Ok(consumption.total_usage)
+}
+
+// Logs once when usage crosses the warning threshold
+fn update_usage(last_usage: &Mutex<Option<u64>>, usage: u64, quota: Option<u64>, warning_ratio: f64) {
+    let before = last_usage.lock().unwrap().replace(usage);
+    if let Some(quota) = quota {
+        let was_near = before.is_some_and(|before| is_near_quota(before, quota, warning_ratio));
+        if !was_near && is_near_quota(usage, quota, warning_ratio) {
+            log::warn!("LINE monthly quota nearly exhausted: {} of {} messages sent", usage, quota);
+        }
+    }
+}
+
+pub async fn refresh() -> Option<u64> {
+    let url = get_config_or("message.quota_consumption_url", DEFAULT_CONSUMPTION_URL.to_string());
+    match fetch_consumption(&get_secret("channel.token"), &url).await {
+        Ok(usage) => {
+            update_usage(&LAST_USAGE, usage, monthly_quota(), warning_ratio());
+            metrics::set_gauge(metrics::LINE_MONTHLY_MESSAGES, usage);
+            Some(usage)
+        }
+        Err(e) => {
+            log::warn!("Failed to read LINE quota consumption: {}", e);
+            *LAST_USAGE.lock().unwrap()
+        }
+    }
+}
+
+pub async fn is_nearly_exhausted() -> bool {
+    let Some(quota) = monthly_quota() else {
+        return false;
+    };
+
+    refresh().await.is_some_and(|usage| is_near_quota(usage, quota, warning_ratio()))
+}
+
+// With line.quota_saver, broadcasts other than the daily summary stop once the quota runs low
+pub async fn should_skip_optional_sends() -> bool {
+    get_config_or("line.quota_saver", false) && is_nearly_exhausted().await
+}
+
+pub async fn refresh_metrics() {
+    metrics::set_gauge(metrics::LINE_MONTHLY_QUOTA, monthly_quota().unwrap_or(0));
+    if is_tracked() {
+        refresh().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use warp::Filter;
+
+    use super::*;
+
+    #[test]
+    fn test_near_quota_threshold() {
+        assert!(!is_near_quota(899, 1000, 0.9));
+        assert!(is_near_quota(900, 1000, 0.9));
+        assert!(is_near_quota(1200, 1000, 0.9));
+    }
+
+    #[test]
+    fn test_update_usage_keeps_last_reported_value() {
+        let last_usage = Mutex::new(None);
+
+        update_usage(&last_usage, 850, Some(1000), 0.9);
+        update_usage(&last_usage, 12_000, Some(1000), 0.9);
+        assert_eq!(*last_usage.lock().unwrap(), Some(12_000));
+    }
+
+    #[tokio::test]
+    async fn test_consumption_is_read_from_line() {
+        let consumption = warp::path!("quota" / "consumption")
+            .and(warp::header::<String>("authorization"))
+            .map(|authorization: String| {
+                assert_eq!(authorization, "Bearer token");
+                warp::reply::json(&serde_json::json!({"totalUsage": 12_345}))
+            });
+        let (addr, server) = warp::serve(consumption).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let usage = fetch_consumption("token", &format!("http://{}/quota/consumption", addr)).await.unwrap();
+        assert_eq!(usage, 12_345);
+    }
+}
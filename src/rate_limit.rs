@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config_helper::get_config_or;
+
+const DEFAULT_RATE_LIMIT_PER_MIN: u32 = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum RateDecision {
+    Allowed,
+    // First request over the limit in this window, the user is told to slow down
+    Throttled,
+    // Further requests in the same window are dropped without a reply
+    Suppressed,
+}
+
+struct Window {
+    started_at: Instant,
+    count: u32,
+}
+
+static WINDOWS: Lazy<Mutex<HashMap<String, Window>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Every message costs ChatGPT and often Kagi calls, so each user gets a fixed window per minute
+pub fn check(user_id: &str) -> RateDecision {
+    let limit = get_config_or("user.rate_limit_per_min", DEFAULT_RATE_LIMIT_PER_MIN);
+    let mut windows = WINDOWS.lock().unwrap();
+    check_at(&mut windows, user_id, limit, RATE_LIMIT_WINDOW, SystemClock.now_instant())
+}
+
+fn check_at(
+    windows: &mut HashMap<String, Window>,
+    user_id: &str,
+    limit: u32,
+    window_length: Duration,
+    now: Instant,
+) -> RateDecision {
+    if limit == 0 {
+        return RateDecision::Allowed;
+    }
+
+    windows.retain(|_, window| now.duration_since(window.started_at) < window_length);
+
+    let window = windows.entry(user_id.to_string()).or_insert(Window {
+        started_at: now,
+        count: 0,
+    });
+    window.count += 1;
+
+    match window.count {
+        count if count <= limit => RateDecision::Allowed,
+        count if count == limit + 1 => RateDecision::Throttled,
+        _ => RateDecision::Suppressed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttles_once_then_suppresses() {
+        let mut windows = HashMap::new();
+        let now = Instant::now();
+        let window_length = Duration::from_secs(60);
+
+        assert_eq!(check_at(&mut windows, "U1", 2, window_length, now), RateDecision::Allowed);
+        assert_eq!(check_at(&mut windows, "U1", 2, window_length, now), RateDecision::Allowed);
+        assert_eq!(check_at(&mut windows, "U1", 2, window_length, now), RateDecision::Throttled);
+        assert_eq!(check_at(&mut windows, "U1", 2, window_length, now), RateDecision::Suppressed);
+        assert_eq!(check_at(&mut windows, "U2", 2, window_length, now), RateDecision::Allowed);
+
+        let later = now + window_length;
+        assert_eq!(check_at(&mut windows, "U1", 2, window_length, later), RateDecision::Allowed);
+        assert_eq!(windows.len(), 1);
+    }
+
+    #[test]
+    fn test_zero_limit_disables_throttling() {
+        let mut windows = HashMap::new();
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(check_at(&mut windows, "U1", 0, Duration::from_secs(60), now), RateDecision::Allowed);
+        }
+    }
+}
@@ -1,61 +1,407 @@
+use std::collections::HashSet;
 use std::error::Error;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
 use rss::{Channel, Item};
-use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
 
-use crate::config_helper::get_config;
+use crate::clock::{Clock, SystemClock};
+use crate::config_helper::{get_config, get_config_or, get_optional_config};
+use crate::{feed, http};
 
-#[derive(Debug, Serialize, Deserialize)]
+const DEFAULT_PER_FEED_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_CONCURRENT_FEEDS: usize = 4;
+const DEFAULT_MAX_STORIES: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Story {
     pub storylink: String,
     pub story: String,
+    // Only feeds with one story per item date their stories; a digest shares one date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published: Option<DateTime<Utc>>,
 }
 
 pub async fn read_feed() -> Result<Channel, Box<dyn Error>> {
+    let content = fetch_feed().await?;
+    let channel = Channel::read_from(&content[..])?;
+    Ok(channel)
+}
+
+pub async fn fetch_feed() -> Result<Bytes, Box<dyn Error>> {
     let url = get_config("rss.feed_url");
-    let content = reqwest::get(url)
+    Ok(fetch_feed_from(&url).await?)
+}
+
+async fn fetch_feed_from(url: &str) -> Result<Bytes, reqwest::Error> {
+    let content = http::send(reqwest::Client::new().get(url))
         .await?
+        .error_for_status()?
         .bytes()
         .await?;
-    let channel = Channel::read_from(&content[..])?;
-    Ok(channel)
+    Ok(content)
 }
 
 pub fn get_latest_item(channel: &rss::Channel) -> Option<Item> {
-    channel.items().first().map(|item| item.clone())
+    channel.items().first().cloned()
+}
+
+#[derive(Default)]
+struct FeedCache {
+    fetched: Option<(Instant, Vec<Story>)>,
+}
+
+impl FeedCache {
+    fn get(&self, now: Instant, ttl: Duration) -> Option<Vec<Story>> {
+        self.fetched
+            .as_ref()
+            .filter(|(fetched_at, _)| now.duration_since(*fetched_at) < ttl)
+            .map(|(_, stories)| stories.clone())
+    }
+
+    fn store(&mut self, now: Instant, stories: Vec<Story>) {
+        self.fetched = Some((now, stories));
+    }
+}
+
+// Holds the aggregated feeds before dedup and limits, so changes to max_stories apply right away
+static FEED_CACHE: Lazy<Mutex<FeedCache>> = Lazy::new(|| Mutex::new(FeedCache::default()));
+
+// rss.cache_ttl_secs = 0 fetches the feeds on every request
+async fn fetch_all_feeds_cached() -> Result<Vec<Story>, String> {
+    let ttl = Duration::from_secs(get_config_or("rss.cache_ttl_secs", 0));
+    if ttl.is_zero() {
+        return fetch_all_feeds().await;
+    }
+    if let Some(stories) = FEED_CACHE.lock().unwrap().get(SystemClock.now_instant(), ttl) {
+        return Ok(stories);
+    }
+    warm_cache(&FEED_CACHE, &SystemClock, fetch_all_feeds()).await
+}
+
+// Best effort: a failed prefetch is logged and the first request fetches the feeds itself
+pub async fn prefetch() {
+    match warm_cache(&FEED_CACHE, &SystemClock, fetch_all_feeds()).await {
+        Ok(stories) => log::info!("Prefetched {} stories", stories.len()),
+        Err(e) => log::warn!("RSS prefetch failed: {}", e),
+    }
+}
+
+async fn warm_cache<Fut>(cache: &Mutex<FeedCache>, clock: &dyn Clock, fetch: Fut) -> Result<Vec<Story>, String>
+where
+    Fut: Future<Output = Result<Vec<Story>, String>>,
+{
+    let stories = fetch.await?;
+    cache.lock().unwrap().store(clock.now_instant(), stories.clone());
+    Ok(stories)
 }
 
 pub async fn get_last_hn_stories() -> Vec<Story> {
-    let channel = read_feed()
+    let stories = fetch_all_feeds_cached()
         .await
         .unwrap_or_else(|err| panic!("read RSS failed: {}", err));
-    let _description = channel.items()[0].description().unwrap();
-
-    // Parse the HTML description to get the story links and titles
-    let html = Html::parse_document(_description);
-    let storylink_selector = Selector::parse(".storylink a").unwrap();
-    let stories = html
-        .select(&storylink_selector)
-        .filter_map(|storylink| {
-            let href = storylink.value().attr("href")?;
-            let title = storylink.text().collect::<String>();
-            Some(Story {
-                storylink: href.to_owned(),
-                story: title,
-            })
-        })
+    let max_stories = get_config_or("rss.max_stories", DEFAULT_MAX_STORIES);
+    let exclude_prefixes: Vec<String> = get_config_or("rss.exclude_prefixes", Vec::new());
+    let stories = exclude_stories(dedup_by_title(stories), &exclude_prefixes, get_config_or("rss.exclude_jobs", false));
+    limit_stories(stories, max_stories)
+}
+
+// Filtered before the list is limited, so excluded posts don't use up slots or indexes
+// A blank prefix would match every title, so it's ignored
+fn exclude_stories(stories: Vec<Story>, prefixes: &[String], exclude_jobs: bool) -> Vec<Story> {
+    let prefixes: Vec<String> = prefixes
+        .iter()
+        .map(|prefix| prefix.trim().to_lowercase())
+        .filter(|prefix| !prefix.is_empty())
         .collect();
     stories
+        .into_iter()
+        .filter(|story| {
+            let title = story.story.trim().to_lowercase();
+            !prefixes.iter().any(|prefix| title.starts_with(prefix))
+        })
+        .filter(|story| !(exclude_jobs && is_job_post(story)))
+        .collect()
+}
+
+// HN job posts are titled "Company (YC W24) Is Hiring ..." and often link to a YC job board;
+// "is hiring" alone also matches ordinary stories about hiring
+fn is_job_post(story: &Story) -> bool {
+    let title = story.story.to_lowercase();
+    let is_hiring = title.contains(" is hiring") || title.contains(" are hiring");
+    (is_hiring && title.contains("(yc "))
+        || ["workatastartup.com", "ycombinator.com/companies"]
+            .iter()
+            .any(|board| story.storylink.contains(board))
+}
+
+// Every caller, including index lookups, sees the same bounded list
+fn limit_stories(mut stories: Vec<Story>, max_stories: usize) -> Vec<Story> {
+    stories.truncate(max_stories);
+    stories
+}
+
+// Reposts show up with the same title, keep the first occurrence
+pub fn dedup_by_title(stories: Vec<Story>) -> Vec<Story> {
+    let mut seen_titles = HashSet::new();
+    stories
+        .into_iter()
+        .filter(|story| seen_titles.insert(normalize_title(&story.story)))
+        .collect()
+}
+
+fn normalize_title(title: &str) -> String {
+    title.split_whitespace().collect::<Vec<&str>>().join(" ").to_lowercase()
+}
+
+fn feed_urls() -> Vec<String> {
+    let urls: Vec<String> = get_config_or("rss.feed_urls", Vec::new());
+    if urls.is_empty() {
+        vec![get_config("rss.feed_url")]
+    } else {
+        urls
+    }
+}
+
+pub async fn fetch_all_feeds() -> Result<Vec<Story>, String> {
+    let per_feed_timeout = Duration::from_secs(get_config_or("rss.per_feed_timeout_secs", DEFAULT_PER_FEED_TIMEOUT_SECS));
+    let max_concurrent_feeds = get_config_or("rss.max_concurrent_feeds", DEFAULT_MAX_CONCURRENT_FEEDS);
+    let format = get_config_or("rss.format", "auto".to_string());
+
+    let fallback = get_optional_config("rss.fallback_feed_url")
+        .filter(|url| !url.trim().is_empty())
+        .map(|url| (url, get_config_or("rss.fallback_format", "auto".to_string())));
+
+    fetch_with_fallback(&feed_urls(), &format, fallback, per_feed_timeout, max_concurrent_feeds).await
+}
+
+// A failover rather than another source: the fallback is only read when every primary feed failed
+async fn fetch_with_fallback(
+    urls: &[String],
+    format: &str,
+    fallback: Option<(String, String)>,
+    per_feed_timeout: Duration,
+    max_concurrent_feeds: usize,
+) -> Result<Vec<Story>, String> {
+    let error = match fetch_feeds(urls, format, per_feed_timeout, max_concurrent_feeds).await {
+        Ok(stories) => return Ok(stories),
+        Err(e) => e,
+    };
+    let Some((fallback_url, fallback_format)) = fallback else {
+        return Err(error);
+    };
+
+    log::warn!("Primary feeds failed ({}), switching to fallback feed {}", error, fallback_url);
+    fetch_feeds(&[fallback_url], &fallback_format, per_feed_timeout, 1).await
+}
+
+// Slow or broken feeds are dropped so one of them can't stall the whole aggregation
+async fn fetch_feeds(
+    urls: &[String],
+    format: &str,
+    per_feed_timeout: Duration,
+    max_concurrent_feeds: usize,
+) -> Result<Vec<Story>, String> {
+    let mut results = stream::iter(urls.iter().cloned().enumerate())
+        .map(|(position, url)| {
+            let format = format.to_string();
+            async move {
+                let result = match timeout(per_feed_timeout, fetch_feed_from(&url)).await {
+                    Ok(Ok(content)) => parse_feed(&content, &format),
+                    Ok(Err(e)) => Err(e.to_string()),
+                    Err(_) => Err(format!("timed out after {:?}", per_feed_timeout)),
+                };
+                (position, url, result)
+            }
+        })
+        .buffer_unordered(max_concurrent_feeds.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    results.sort_by_key(|(position, _, _)| *position);
+
+    let mut stories = Vec::new();
+    let mut succeeded = false;
+    for (_, url, result) in results {
+        match result {
+            Ok(feed_stories) => {
+                succeeded = true;
+                stories.extend(feed_stories);
+            }
+            Err(e) => log::warn!("Skipping feed {}: {}", url, e),
+        }
+    }
+
+    if !succeeded {
+        return Err("all feeds failed".to_string());
+    }
+    Ok(stories)
+}
+
+fn parse_feed(content: &[u8], format: &str) -> Result<Vec<Story>, String> {
+    let selector = feed::StorySelector::from_config().map_err(|e| e.to_string())?;
+    feed::parse_with_format(content, format, &selector).map_err(|e| e.to_string())
 }
 
 #[cfg(test)]
 mod tests {
+    use warp::Filter;
+
     use super::*;
 
+    #[tokio::test]
+    async fn test_prefetch_populates_cache() {
+        let cache = Mutex::new(FeedCache::default());
+        let clock = crate::clock::MockClock::new(chrono::Utc::now());
+        let ttl = Duration::from_secs(300);
+        let stories = vec![Story {
+            storylink: "https://example.com/1".to_string(),
+            story: "Story 1".to_string(),
+            published: None,
+        }];
+
+        assert!(cache.lock().unwrap().get(clock.now_instant(), ttl).is_none());
+        warm_cache(&cache, &clock, async { Ok(stories.clone()) }).await.unwrap();
+
+        let cached = cache.lock().unwrap().get(clock.now_instant(), ttl).unwrap();
+        assert_eq!(cached[0].storylink, "https://example.com/1");
+        clock.advance(ttl);
+        assert!(cache.lock().unwrap().get(clock.now_instant(), ttl).is_none());
+    }
+
     #[tokio::test]
     async fn get_last_hn_stories_returns_stories_for_valid_feed() {
         let stories = get_last_hn_stories().await;
         println!("{:?}", stories);
     }
-}
\ No newline at end of file
+
+    const SAMPLE_RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Front page</title>
+    <link>https://example.com/</link>
+    <description>Stories</description>
+    <item><title>Fast story</title><link>https://example.com/fast</link></item>
+  </channel>
+</rss>"#;
+
+    #[tokio::test]
+    async fn fetch_feeds_skips_hanging_feed() {
+        let fast = warp::path("fast").map(|| SAMPLE_RSS);
+        let slow = warp::path("slow").and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            Ok::<_, warp::Rejection>(SAMPLE_RSS)
+        });
+        let (addr, server) = warp::serve(fast.or(slow)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let urls = vec![format!("http://{}/slow", addr), format!("http://{}/fast", addr)];
+        let started = std::time::Instant::now();
+        let stories = fetch_feeds(&urls, "auto", Duration::from_millis(300), 2).await.unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].story, "Fast story");
+    }
+
+    #[test]
+    fn dedup_by_title_keeps_first_occurrence() {
+        let story = |title: &str, link: &str| Story {
+            storylink: link.to_string(),
+            story: title.to_string(),
+            published: None,
+        };
+        let stories = vec![
+            story("Show HN: My Rust project", "https://example.com/1"),
+            story("Another story", "https://example.com/2"),
+            story("  show hn:  my rust   PROJECT ", "https://example.com/3"),
+        ];
+
+        let deduped = dedup_by_title(stories);
+
+        assert_eq!(deduped.len(), 2);
+        assert_eq!(deduped[0].storylink, "https://example.com/1");
+        assert_eq!(deduped[1].story, "Another story");
+    }
+
+    #[tokio::test]
+    async fn fetch_feeds_errors_when_all_fail() {
+        let urls = vec!["http://127.0.0.1:9/unreachable".to_string()];
+
+        assert!(fetch_feeds(&urls, "auto", Duration::from_millis(300), 2).await.is_err());
+    }
+
+    #[test]
+    fn exclude_stories_removes_ask_show_and_job_posts() {
+        let story = |title: &str, link: &str| Story {
+            storylink: link.to_string(),
+            story: title.to_string(),
+            published: None,
+        };
+        let stories = vec![
+            story("Rust 2.0 released", "https://example.com/rust"),
+            story("Ask HN: What are you working on?", "https://news.ycombinator.com/item?id=1"),
+            story("Show HN: A tiny LINE bot", "https://example.com/bot"),
+            story("Acme (YC W24) Is Hiring Rust Engineers", "https://www.ycombinator.com/companies/acme/jobs"),
+            story("SQLite internals", "https://example.com/sqlite"),
+            story("Why nobody is hiring junior developers", "https://example.com/juniors"),
+        ];
+        let prefixes = vec!["Ask HN:".to_string(), "show hn:".to_string(), "  ".to_string()];
+
+        let titles = |stories: Vec<Story>| stories.into_iter().map(|story| story.story).collect::<Vec<String>>();
+        assert_eq!(
+            titles(exclude_stories(stories.clone(), &prefixes, false)),
+            vec![
+                "Rust 2.0 released",
+                "Acme (YC W24) Is Hiring Rust Engineers",
+                "SQLite internals",
+                "Why nobody is hiring junior developers"
+            ]
+        );
+        assert_eq!(
+            titles(exclude_stories(stories.clone(), &prefixes, true)),
+            vec!["Rust 2.0 released", "SQLite internals", "Why nobody is hiring junior developers"]
+        );
+        assert_eq!(exclude_stories(stories.clone(), &[], false).len(), 6);
+        assert_eq!(exclude_stories(stories, &["".to_string()], false).len(), 6);
+    }
+
+    #[test]
+    fn limit_stories_truncates_to_max() {
+        let stories: Vec<Story> = (1..=30)
+            .map(|i| Story {
+                storylink: format!("https://example.com/{}", i),
+                story: format!("Story {}", i),
+                published: None,
+            })
+            .collect();
+
+        let limited = limit_stories(stories, 10);
+
+        assert_eq!(limited.len(), 10);
+        assert_eq!(limited[9].story, "Story 10");
+        assert_eq!(limit_stories(limited, 20).len(), 10);
+    }
+
+    #[tokio::test]
+    async fn fetch_falls_back_when_primary_fails() {
+        let primary = warp::path("primary").map(|| warp::reply::with_status("down", warp::http::StatusCode::INTERNAL_SERVER_ERROR));
+        let fallback = warp::path("fallback").map(|| r#"{"hits":[{"title":"Fallback story","url":"https://example.com/fallback","objectID":"1"}]}"#);
+        let (addr, server) = warp::serve(primary.or(fallback)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let urls = vec![format!("http://{}/primary", addr)];
+        let fallback = Some((format!("http://{}/fallback", addr), "algolia".to_string()));
+        let stories = fetch_with_fallback(&urls, "auto", fallback, Duration::from_secs(5), 2).await.unwrap();
+
+        assert_eq!(stories.len(), 1);
+        assert_eq!(stories[0].story, "Fallback story");
+        assert!(fetch_with_fallback(&urls, "auto", None, Duration::from_secs(5), 2).await.is_err());
+    }
+}
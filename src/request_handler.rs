@@ -1,25 +1,268 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap};
-use serde_json::json;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
 use warp::{
     Rejection, Reply,
 };
 use uuid::Uuid;
 
-pub async fn handle_send_request(
+use crate::clock::{Clock, SystemClock};
+use crate::config_helper::{get_config, get_config_or};
+use crate::{http, quota, redact};
+use crate::line_helper::{
+    LineErrorResponse, LineLoadingRequest, LineMessage, LineMessageRequest, LineNarrowcastProgress, LineNarrowcastRequest,
+    LineSendMessageRequest,
+};
+
+const DEFAULT_LOADING_SECONDS: u32 = 20;
+
+pub async fn handle_reply_request(
     token: &str,
-    json_body: String,
+    reply_token: &str,
+    user_id: Option<&str>,
+    messages: Vec<LineMessage>,
+) -> Result<impl Reply + Sized, Rejection> {
+    let reply_url = get_config("message.reply_url");
+    let push_url = get_config("message.push_url");
+
+    let result = reply_with_push_fallback(token, &reply_url, &push_url, reply_token, user_id, messages)
+        .await
+        .map(|(path, response)| {
+            log::info!("LINE reply delivered via {:?}", path);
+            response
+        });
+    send_result_to_reply(result).await
+}
+
+// One logical send: every attempt carries the same X-Line-Retry-Key, so LINE drops
+// duplicates of a request it already accepted
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendId(Uuid);
+
+impl SendId {
+    pub fn new() -> Self {
+        SendId(Uuid::new_v4())
+    }
+
+    // The same body sent to the same endpoint within line.retry_key_window_secs is treated as
+    // a repeat of the earlier send, e.g. a cron job that fires twice
+    pub fn for_body(url: &str, json_body: &str) -> Self {
+        let window = Duration::from_secs(get_config_or("line.retry_key_window_secs", 0));
+        let mut recent = RECENT_SENDS.lock().unwrap();
+        send_id_for_body(&mut recent, url, json_body, window, SystemClock.now_instant())
+    }
+
+    pub fn retry_key(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+static RECENT_SENDS: Lazy<Mutex<HashMap<u64, (SendId, Instant)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn send_id_for_body(
+    recent: &mut HashMap<u64, (SendId, Instant)>,
     url: &str,
+    json_body: &str,
+    window: Duration,
+    now: Instant,
+) -> SendId {
+    if window.is_zero() {
+        return SendId::new();
+    }
+
+    recent.retain(|_, (_, sent_at)| now.duration_since(*sent_at) < window);
+    let mut hasher = DefaultHasher::new();
+    (url, json_body).hash(&mut hasher);
+    recent.entry(hasher.finish()).or_insert_with(|| (SendId::new(), now)).0.clone()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReplyPath {
+    Reply,
+    RetriedReply,
+    Push,
+}
+
+#[derive(Debug)]
+enum ReplyFailure {
+    // The token expired or was already used, only a push can still reach the user
+    InvalidToken,
+    // Network errors and 5xx responses, worth one more reply attempt since replies are free
+    Transient(String),
+    Rejected(String),
+}
+
+// Reply tokens are single-use and expire after about a minute, so a slow conversation
+// turn falls back to pushing the same messages to the user instead
+pub async fn reply_with_push_fallback(
+    token: &str,
+    reply_url: &str,
+    push_url: &str,
+    reply_token: &str,
+    user_id: Option<&str>,
+    messages: Vec<LineMessage>,
+) -> Result<(ReplyPath, reqwest::Response), Box<dyn Error + Send + Sync>> {
+    let reply_body = serde_json::to_string(&LineMessageRequest {
+        reply_token: reply_token.to_string(),
+        messages: messages.clone(),
+    })?;
+
+    let send_id = SendId::new();
+    let mut path = ReplyPath::Reply;
+    let mut result = try_reply(token, reply_url, reply_body.clone(), &send_id).await;
+    if let Err(ReplyFailure::Transient(e)) = &result {
+        log::warn!("LINE reply failed, retrying once: {}", e);
+        path = ReplyPath::RetriedReply;
+        result = try_reply(token, reply_url, reply_body, &send_id).await;
+    }
+
+    let failure = match result {
+        Ok(response) => return Ok((path, response)),
+        Err(ReplyFailure::Rejected(error_body)) => return Err(format!("LINE reply failed: {}", error_body).into()),
+        Err(failure) => failure,
+    };
+    let Some(user_id) = user_id else {
+        return Err(format!("LINE reply failed: {:?}", failure).into());
+    };
+
+    log::warn!("Reply could not be delivered ({:?}), pushing the messages instead", failure);
+    let push_body = serde_json::to_string(&LineSendMessageRequest {
+        to: user_id.to_string(),
+        messages,
+    })?;
+    Ok((ReplyPath::Push, send_billed_request(token, push_body, push_url, &SendId::new()).await?))
+}
+
+async fn try_reply(token: &str, reply_url: &str, reply_body: String, send_id: &SendId) -> Result<reqwest::Response, ReplyFailure> {
+    let response = send_request(token, reply_body, reply_url, send_id)
+        .await
+        .map_err(|e| ReplyFailure::Transient(e.to_string()))?;
+
+    let status = response.status();
+    if status.is_server_error() {
+        return Err(ReplyFailure::Transient(format!("LINE reply returned {}", status)));
+    }
+    if status != StatusCode::BAD_REQUEST {
+        return Ok(response);
+    }
+
+    let error_body = response.text().await.map_err(|e| ReplyFailure::Transient(e.to_string()))?;
+    if is_invalid_reply_token(&error_body) {
+        Err(ReplyFailure::InvalidToken)
+    } else {
+        Err(ReplyFailure::Rejected(error_body))
+    }
+}
+
+// Best effort: a missing loading animation shouldn't stop the reply
+pub async fn show_loading(token: &str, user_id: &str) {
+    let seconds = get_config_or("line.loading_seconds", DEFAULT_LOADING_SECONDS);
+    if seconds == 0 {
+        return;
+    }
+
+    let url = get_config("message.loading_url");
+    if let Err(e) = send_loading(token, &url, user_id, seconds).await {
+        log::warn!("Failed to show the loading animation: {}", e);
+    }
+}
+
+async fn send_loading(token: &str, url: &str, user_id: &str, seconds: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let json_body = serde_json::to_string(&LineLoadingRequest {
+        chat_id: user_id.to_string(),
+        loading_seconds: loading_seconds(seconds),
+    })?;
+
+    let response = send_request(token, json_body, url, &SendId::new()).await?;
+    if !response.status().is_success() {
+        return Err(format!("LINE loading request failed: {}", response.text().await?).into());
+    }
+    Ok(())
+}
+
+// LINE only accepts multiples of 5 between 5 and 60 seconds
+fn loading_seconds(seconds: u32) -> u32 {
+    seconds.div_ceil(5).clamp(1, 12) * 5
+}
+
+// Narrowcast is asynchronous: LINE accepts the request and returns an ID to poll for progress
+pub async fn narrowcast_message(
+    token: &str,
+    recipient: Option<Value>,
+    filter: Option<Value>,
+    messages: Vec<LineMessage>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let url = get_config("message.narrowcast_url");
+    send_narrowcast(token, &url, LineNarrowcastRequest { messages, recipient, filter }).await
+}
+
+async fn send_narrowcast(token: &str, url: &str, request: LineNarrowcastRequest) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let json_body = serde_json::to_string(&request)?;
+
+    let response = send_billed_request(token, json_body, url, &SendId::new()).await?;
+    if !response.status().is_success() {
+        return Err(format!("LINE narrowcast failed: {}", redact::redact(&response.text().await?)).into());
+    }
+
+    response
+        .headers()
+        .get("x-line-request-id")
+        .and_then(|request_id| request_id.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| "LINE narrowcast response has no request ID".into())
+}
+
+pub async fn get_narrowcast_progress(token: &str, request_id: &str) -> Result<LineNarrowcastProgress, Box<dyn Error + Send + Sync>> {
+    let url = get_config("message.narrowcast_progress_url");
+    fetch_narrowcast_progress(token, &url, request_id).await
+}
+
+async fn fetch_narrowcast_progress(
+    token: &str,
+    url: &str,
+    request_id: &str,
+) -> Result<LineNarrowcastProgress, Box<dyn Error + Send + Sync>> {
+    let request = reqwest::Client::new()
+        .get(url)
+        .query(&[("requestId", request_id)])
+        .header(AUTHORIZATION, format!("Bearer {}", token));
+    let response = http::send(request).await?;
+    if !response.status().is_success() {
+        return Err(format!("LINE narrowcast progress failed: {}", response.text().await?).into());
+    }
+
+    Ok(serde_json::from_str(&response.text().await?)?)
+}
+
+pub fn is_invalid_reply_token(error_body: &str) -> bool {
+    serde_json::from_str::<LineErrorResponse>(error_body)
+        .map(|error| error.message.to_lowercase().contains("invalid reply token"))
+        .unwrap_or(false)
+}
+
+async fn send_result_to_reply<E: Display>(
+    result: Result<reqwest::Response, E>,
 ) -> Result<impl Reply + Sized, Rejection> {
-    match send_request(token, json_body, url).await {
+    match result {
         Ok(_response) => {            
-            log::info!("LINE Message API response: {}", _response.text().await.unwrap());
+            log::info!("LINE Message API response: {}", redact::redact(&_response.text().await.unwrap()));
             
             Ok(warp::reply::with_status(
             warp::reply::json(&json!({"success": true})),
             warp::http::StatusCode::OK,
         ))},
         Err(_error) => {
-            log::error!("LINE Message API error: {}", _error.to_string());
+            log::error!("LINE Message API error: {}", redact::redact(&_error.to_string()));
             Ok(warp::reply::with_status(
             warp::reply::json(&json!({"success": false, "error": _error.to_string()})),
             warp::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -27,23 +270,299 @@ pub async fn handle_send_request(
     }
 }
 
+// Pushes, multicasts and broadcasts count against the monthly quota; LINE is asked for the new
+// usage in the background once it accepts one
+pub async fn send_billed_request(
+    token: &str,
+    json_body: String,
+    url: &str,
+    send_id: &SendId,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let response = send_request(token, json_body, url, send_id).await?;
+    if response.status().is_success() && quota::is_tracked() {
+        tokio::spawn(quota::refresh());
+    }
+    Ok(response)
+}
+
 pub async fn send_request(
     token: &str,
     json_body: String,
     url: &str,
+    send_id: &SendId,
 ) -> Result<reqwest::Response, reqwest::Error> {
     let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
     headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
     headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
-    headers.insert("X-Line-Retry-Key", Uuid::new_v4().to_string().parse().unwrap());
+    headers.insert("X-Line-Retry-Key", send_id.retry_key().parse().unwrap());
 
-    let response = client
-        .post(url)
-        .headers(headers)
-        .body(json_body)
-        .send()
-        .await?;
+    let response = http::send(client.post(url).headers(headers).body(json_body)).await?;
 
     Ok(response)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use bytes::Bytes;
+    use warp::Filter;
+
+    use super::*;
+
+    // Serves /reply with the given status and body, and records every /push body
+    async fn spawn_line_mock(reply_status: u16, reply_body: &'static str) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let pushes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = pushes.clone();
+
+        let reply = warp::post().and(warp::path("reply")).map(move || {
+            warp::reply::with_status(reply_body, warp::http::StatusCode::from_u16(reply_status).unwrap())
+        });
+        let push = warp::post()
+            .and(warp::path("push"))
+            .and(warp::body::bytes())
+            .map(move |body: Bytes| {
+                recorded.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+                "{}"
+            });
+
+        let (addr, server) = warp::serve(reply.or(push)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (addr, pushes)
+    }
+
+    fn text_message(text: &str) -> LineMessage {
+        LineMessage {
+            message_type: "text".to_string(),
+            text: text.to_string(),
+            alt_text: None,
+            template: None,
+            sender: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expired_reply_token_falls_back_to_push() {
+        let (addr, pushes) = spawn_line_mock(400, r#"{"message":"Invalid reply token"}"#).await;
+
+        let (path, response) = reply_with_push_fallback(
+            "token",
+            &format!("http://{}/reply", addr),
+            &format!("http://{}/push", addr),
+            "expired-reply-token",
+            Some("U1234"),
+            vec![text_message("latest stories")],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(path, ReplyPath::Push);
+        assert!(response.status().is_success());
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 1);
+        let push: serde_json::Value = serde_json::from_str(&pushes[0]).unwrap();
+        assert_eq!(push["to"], "U1234");
+        assert_eq!(push["messages"][0]["text"], "latest stories");
+    }
+
+    #[tokio::test]
+    async fn test_other_reply_errors_do_not_push() {
+        let (addr, pushes) = spawn_line_mock(400, r#"{"message":"The request body has 1 error(s)"}"#).await;
+
+        let result = reply_with_push_fallback(
+            "token",
+            &format!("http://{}/reply", addr),
+            &format!("http://{}/push", addr),
+            "reply-token",
+            Some("U1234"),
+            vec![text_message("latest stories")],
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(pushes.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transient_reply_failure_is_retried() {
+        let replies = Arc::new(AtomicUsize::new(0));
+        let counter = replies.clone();
+        let reply = warp::post().and(warp::path("reply")).map(move || {
+            let status = match counter.fetch_add(1, Ordering::SeqCst) {
+                0 => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                _ => warp::http::StatusCode::OK,
+            };
+            warp::reply::with_status("{}", status)
+        });
+        let (addr, server) = warp::serve(reply).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let (path, response) = reply_with_push_fallback(
+            "token",
+            &format!("http://{}/reply", addr),
+            &format!("http://{}/push", addr),
+            "reply-token",
+            Some("U1234"),
+            vec![text_message("latest stories")],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(path, ReplyPath::RetriedReply);
+        assert!(response.status().is_success());
+        assert_eq!(replies.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retried_reply_reuses_retry_key() {
+        let keys = Arc::new(Mutex::new(Vec::new()));
+        let recorded = keys.clone();
+        let reply = warp::post()
+            .and(warp::path("reply"))
+            .and(warp::header::<String>("x-line-retry-key"))
+            .map(move |key: String| {
+                let mut keys = recorded.lock().unwrap();
+                keys.push(key);
+                let status = match keys.len() {
+                    1 => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                    _ => warp::http::StatusCode::OK,
+                };
+                warp::reply::with_status("{}", status)
+            });
+        let (addr, server) = warp::serve(reply).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let reply_url = format!("http://{}/reply", addr);
+        let push_url = format!("http://{}/push", addr);
+
+        for _ in 0..2 {
+            reply_with_push_fallback("token", &reply_url, &push_url, "reply-token", None, vec![text_message("hi")])
+                .await
+                .unwrap();
+        }
+
+        let keys = keys.lock().unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[test]
+    fn test_send_id_reused_within_window() {
+        let mut recent = HashMap::new();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        let body = r#"{"messages":[]}"#;
+
+        let first = send_id_for_body(&mut recent, "broadcast", body, window, now);
+        let repeat = send_id_for_body(&mut recent, "broadcast", body, window, now + Duration::from_secs(30));
+        let other = send_id_for_body(&mut recent, "broadcast", r#"{"messages":[1]}"#, window, now);
+        let later = send_id_for_body(&mut recent, "broadcast", body, window, now + Duration::from_secs(90));
+
+        assert_eq!(first, repeat);
+        assert_ne!(first, other);
+        assert_ne!(first, later);
+        assert_ne!(
+            send_id_for_body(&mut recent, "broadcast", body, Duration::ZERO, now),
+            send_id_for_body(&mut recent, "broadcast", body, Duration::ZERO, now)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_server_errors_fall_back_to_push() {
+        let (addr, pushes) = spawn_line_mock(500, "{}").await;
+
+        let (path, _) = reply_with_push_fallback(
+            "token",
+            &format!("http://{}/reply", addr),
+            &format!("http://{}/push", addr),
+            "reply-token",
+            Some("U1234"),
+            vec![text_message("latest stories")],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(path, ReplyPath::Push);
+        assert_eq!(pushes.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_narrowcast_returns_request_id_and_progress() {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let narrowcast = warp::post()
+            .and(warp::path("narrowcast"))
+            .and(warp::body::json())
+            .map(move |body: Value| {
+                recorded.lock().unwrap().push(body);
+                let reply = warp::reply::with_header("{}", "x-line-request-id", "req-42");
+                warp::reply::with_status(reply, warp::http::StatusCode::ACCEPTED)
+            });
+        let progress = warp::get()
+            .and(warp::path("progress"))
+            .and(warp::query::<HashMap<String, String>>())
+            .map(|query: HashMap<String, String>| {
+                assert_eq!(query["requestId"], "req-42");
+                r#"{"phase":"succeeded","successCount":120,"failureCount":0,"targetCount":120,"acceptedTime":"2026-10-16T08:00:00Z"}"#
+            });
+        let (addr, server) = warp::serve(narrowcast.or(progress)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let filter = json!({"demographic": {"type": "area", "oneOf": ["tw_01"]}});
+        let request = LineNarrowcastRequest {
+            messages: vec![text_message("digest")],
+            recipient: None,
+            filter: Some(filter.clone()),
+        };
+        let request_id = send_narrowcast("token", &format!("http://{}/narrowcast", addr), request).await.unwrap();
+        let progress = fetch_narrowcast_progress("token", &format!("http://{}/progress", addr), &request_id)
+            .await
+            .unwrap();
+
+        assert_eq!(request_id, "req-42");
+        assert_eq!(
+            bodies.lock().unwrap().as_slice(),
+            [json!({"messages": [{"type": "text", "text": "digest"}], "filter": filter})]
+        );
+        assert_eq!(progress.phase, "succeeded");
+        assert_eq!(progress.success_count, Some(120));
+    }
+
+    #[test]
+    fn test_is_invalid_reply_token() {
+        assert!(is_invalid_reply_token(r#"{"message":"Invalid reply token"}"#));
+        assert!(!is_invalid_reply_token(r#"{"message":"Authentication failed"}"#));
+        assert!(!is_invalid_reply_token("not json"));
+    }
+
+    #[tokio::test]
+    async fn test_show_loading_posts_chat_id_and_seconds() {
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        let loading = warp::post()
+            .and(warp::path!("chat" / "loading" / "start"))
+            .and(warp::body::bytes())
+            .map(move |body: Bytes| {
+                recorded.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+                warp::reply::with_status("{}", warp::http::StatusCode::ACCEPTED)
+            });
+        let (addr, server) = warp::serve(loading).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        send_loading("token", &format!("http://{}/chat/loading/start", addr), "U1234", 18)
+            .await
+            .unwrap();
+
+        assert_eq!(bodies.lock().unwrap().as_slice(), [r#"{"chatId":"U1234","loadingSeconds":20}"#]);
+    }
+
+    #[test]
+    fn test_loading_seconds_bounds() {
+        assert_eq!(loading_seconds(1), 5);
+        assert_eq!(loading_seconds(20), 20);
+        assert_eq!(loading_seconds(300), 60);
+    }
+}
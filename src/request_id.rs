@@ -0,0 +1,73 @@
+use std::convert::Infallible;
+use std::future::Future;
+
+use uuid::Uuid;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+use crate::config_helper::get_config_or;
+
+const DEFAULT_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_CHARS: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Reuses the ID a gateway put in server.request_id_header so our log lines match its traces
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        from_headers(&headers, &get_config_or("server.request_id_header", DEFAULT_HEADER.to_string()))
+    })
+}
+
+// IDs that could break a log line are replaced with a fresh one
+fn from_headers(headers: &HeaderMap, header: &str) -> String {
+    headers
+        .get(header)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_CHARS && id.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+pub async fn scope<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+// Read by the log format, so every line logged while handling a request carries its ID
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_headers_reuses_gateway_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "gw-1234".parse().unwrap());
+
+        assert_eq!(from_headers(&headers, "x-request-id"), "gw-1234");
+        assert!(Uuid::parse_str(&from_headers(&HeaderMap::new(), "x-request-id")).is_ok());
+
+        headers.insert("x-request-id", "two words".parse().unwrap());
+        assert_ne!(from_headers(&headers, "x-request-id"), "two words");
+    }
+
+    #[tokio::test]
+    async fn test_provided_id_is_in_log_context() {
+        let request_id = warp::test::request()
+            .header("x-request-id", "gw-5678")
+            .filter(&request_id())
+            .await
+            .unwrap();
+
+        let logged = scope(request_id, async { current() }).await;
+
+        assert_eq!(logged.as_deref(), Some("gw-5678"));
+        assert_eq!(current(), None);
+    }
+}
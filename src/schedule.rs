@@ -0,0 +1,56 @@
+use chrono::{DateTime, Datelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config_helper::get_config_or;
+use crate::summary;
+
+// Broadcasts only go out on schedule.days, judged by the weekday in summary.timezone;
+// leaving it empty keeps every day
+pub fn should_run_today() -> bool {
+    should_run_on(SystemClock.now_utc())
+}
+
+pub fn should_run_on(at: DateTime<Utc>) -> bool {
+    is_scheduled_day(at, summary::configured_timezone(), &configured_days())
+}
+
+fn configured_days() -> Vec<Weekday> {
+    let days: Vec<String> = get_config_or("schedule.days", Vec::new());
+    days.iter()
+        .filter_map(|day| match day.trim().parse::<Weekday>() {
+            Ok(weekday) => Some(weekday),
+            Err(_) => {
+                log::warn!("Ignoring unknown day {:?} in schedule.days", day);
+                None
+            }
+        })
+        .collect()
+}
+
+fn is_scheduled_day(at: DateTime<Utc>, timezone: Tz, days: &[Weekday]) -> bool {
+    days.is_empty() || days.contains(&at.with_timezone(&timezone).weekday())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn test_weekend_is_skipped_in_local_time() {
+        let weekdays = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri];
+        let taipei: Tz = "Asia/Taipei".parse().unwrap();
+        // Friday 23:00 in Taipei
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2026, 10, 16, 15, 0, 0).unwrap());
+
+        assert!(is_scheduled_day(clock.now_utc(), taipei, &weekdays));
+        // Saturday 01:00 in Taipei while it is still Friday in UTC
+        clock.advance(std::time::Duration::from_secs(2 * 60 * 60));
+        assert!(!is_scheduled_day(clock.now_utc(), taipei, &weekdays));
+        assert!(is_scheduled_day(clock.now_utc(), Tz::UTC, &weekdays));
+        assert!(is_scheduled_day(clock.now_utc(), taipei, &[]));
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config_helper::get_config_or;
+use crate::readrss::{self, Story};
+
+const BROADCAST_SNAPSHOT_KEY: &str = "__broadcast__";
+const DEFAULT_SNAPSHOT_TTL_SECS: u64 = 6 * 60 * 60;
+
+struct Snapshot {
+    stories: Vec<Story>,
+    taken_at: Instant,
+}
+
+static SNAPSHOTS: Lazy<Mutex<HashMap<String, Snapshot>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn snapshot_ttl() -> Duration {
+    Duration::from_secs(get_config_or("stories.snapshot_ttl_secs", DEFAULT_SNAPSHOT_TTL_SECS))
+}
+
+// Index-based requests resolve against the list the user was shown, not the live feed
+pub fn save_snapshot(user_id: &str, stories: &[Story]) {
+    let mut snapshots = SNAPSHOTS.lock().unwrap();
+    let ttl = snapshot_ttl();
+    snapshots.retain(|_, snapshot| snapshot.taken_at.elapsed() < ttl);
+    snapshots.insert(
+        user_id.to_string(),
+        Snapshot {
+            stories: stories.to_vec(),
+            taken_at: Instant::now(),
+        },
+    );
+}
+
+pub fn save_broadcast_snapshot(stories: &[Story]) {
+    save_snapshot(BROADCAST_SNAPSHOT_KEY, stories);
+}
+
+pub fn snapshot_for(user_id: &str) -> Option<Vec<Story>> {
+    snapshot_for_with_ttl(user_id, snapshot_ttl())
+}
+
+fn snapshot_for_with_ttl(user_id: &str, ttl: Duration) -> Option<Vec<Story>> {
+    let snapshots = SNAPSHOTS.lock().unwrap();
+    [user_id, BROADCAST_SNAPSHOT_KEY]
+        .iter()
+        .filter_map(|key| snapshots.get(*key))
+        .find(|snapshot| snapshot.taken_at.elapsed() < ttl)
+        .map(|snapshot| snapshot.stories.clone())
+}
+
+// Users and ChatGPT number stories from 1; this is the only place that maps them to positions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoryIndex(usize);
+
+impl StoryIndex {
+    pub fn new(rank: usize) -> Option<Self> {
+        (rank >= 1).then_some(StoryIndex(rank))
+    }
+
+    pub fn from_position(position: usize) -> Self {
+        StoryIndex(position + 1)
+    }
+
+    pub fn rank(self) -> usize {
+        self.0
+    }
+
+    pub fn pick(self, stories: &[Story]) -> Option<&Story> {
+        stories.get(self.0 - 1)
+    }
+}
+
+pub async fn resolve_index(user_id: &str, index: StoryIndex) -> Option<Story> {
+    let stories = match snapshot_for(user_id) {
+        Some(stories) => stories,
+        None => {
+            let stories = readrss::get_last_hn_stories().await;
+            save_snapshot(user_id, &stories);
+            stories
+        }
+    };
+
+    index.pick(&stories).cloned()
+}
+
+#[derive(Debug, Serialize)]
+pub struct LatestStoriesView {
+    pub generated_at: DateTime<Utc>,
+    pub source: String,
+    pub stories: Vec<StoryView>,
+}
+
+// Public shape of /getLatestStories, kept separate from the internal Story
+#[derive(Debug, Serialize)]
+pub struct StoryView {
+    pub rank: usize,
+    pub title: String,
+    pub url: String,
+    pub points: Option<u32>,
+    pub comments_url: Option<String>,
+    pub domain: Option<String>,
+}
+
+impl StoryView {
+    pub fn from_story(rank: usize, story: &Story) -> Self {
+        StoryView {
+            rank,
+            title: story.story.clone(),
+            url: story.storylink.clone(),
+            points: None,
+            comments_url: None,
+            domain: domain_of(&story.storylink),
+        }
+    }
+}
+
+pub fn to_latest_stories_view(stories: &[Story], source: String, generated_at: DateTime<Utc>) -> LatestStoriesView {
+    LatestStoriesView {
+        generated_at,
+        source,
+        stories: stories
+            .iter()
+            .enumerate()
+            .map(|(i, story)| StoryView::from_story(StoryIndex::from_position(i).rank(), story))
+            .collect(),
+    }
+}
+
+pub fn domain_of(url: &str) -> Option<String> {
+    let host = reqwest::Url::parse(url).ok()?.host_str()?.to_lowercase();
+    Some(host.strip_prefix("www.").map(str::to_string).unwrap_or(host))
+}
+
+// A label for example.com also covers its subdomains, such as blog.example.com;
+// when both match, the longer domain is the more specific label
+pub fn domain_label<'a>(url: &str, labels: &'a HashMap<String, String>) -> Option<&'a str> {
+    let domain = domain_of(url)?;
+    labels
+        .iter()
+        .filter(|(labelled, _)| {
+            let labelled = labelled.to_lowercase();
+            domain == labelled || domain.ends_with(&format!(".{}", labelled))
+        })
+        .max_by_key(|(labelled, _)| labelled.len())
+        .map(|(_, label)| label.as_str())
+}
+
+// Dates in the future, from clock skew, read as just now
+pub fn relative_time(published: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let age = now.signed_duration_since(published);
+    match (age.num_minutes(), age.num_hours(), age.num_days()) {
+        (minutes, _, _) if minutes < 1 => "just now".to_string(),
+        (minutes, 0, _) => format!("{}m ago", minutes),
+        (_, hours, 0) => format!("{}h ago", hours),
+        (_, _, 1) => "yesterday".to_string(),
+        (_, _, days) => format!("{}d ago", days),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn story(title: &str) -> Story {
+        Story {
+            storylink: format!("https://example.com/{}", title),
+            story: title.to_string(),
+            published: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_index_uses_snapshot() {
+        save_snapshot("user-snapshot", &[story("first"), story("second"), story("third")]);
+
+        let resolved = resolve_index("user-snapshot", StoryIndex::new(3).unwrap()).await.unwrap();
+        assert_eq!(resolved.story, "third");
+
+        assert!(resolve_index("user-snapshot", StoryIndex::new(4).unwrap()).await.is_none());
+    }
+
+    #[test]
+    fn test_relative_time() {
+        let now = "2026-10-16T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let ago = |minutes: i64| now - chrono::Duration::minutes(minutes);
+
+        assert_eq!(relative_time(now, now), "just now");
+        assert_eq!(relative_time(now + chrono::Duration::minutes(5), now), "just now");
+        assert_eq!(relative_time(ago(45), now), "45m ago");
+        assert_eq!(relative_time(ago(3 * 60 + 20), now), "3h ago");
+        assert_eq!(relative_time(ago(30 * 60), now), "yesterday");
+        assert_eq!(relative_time(ago(3 * 24 * 60), now), "3d ago");
+    }
+
+    #[test]
+    fn test_story_index_boundaries() {
+        let stories = [story("first"), story("second"), story("third")];
+
+        assert!(StoryIndex::new(0).is_none());
+        assert_eq!(StoryIndex::new(1).unwrap().pick(&stories).unwrap().story, "first");
+        assert_eq!(StoryIndex::new(3).unwrap().pick(&stories).unwrap().story, "third");
+        assert!(StoryIndex::new(4).unwrap().pick(&stories).is_none());
+        assert_eq!(StoryIndex::from_position(0), StoryIndex::new(1).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_is_per_user() {
+        save_snapshot("user-a", &[story("a")]);
+        save_snapshot("user-b", &[story("b")]);
+
+        assert_eq!(snapshot_for("user-a").unwrap()[0].story, "a");
+        assert_eq!(snapshot_for("user-b").unwrap()[0].story, "b");
+    }
+
+    #[test]
+    fn test_expired_snapshot_is_ignored() {
+        save_snapshot("user-expired", &[story("old")]);
+
+        assert!(snapshot_for_with_ttl("user-expired", Duration::from_secs(3600)).is_some());
+        assert!(snapshot_for_with_ttl("user-expired", Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_latest_stories_view_json_shape() {
+        let stories = vec![
+            Story {
+                storylink: "https://www.example.com/post".to_string(),
+                story: "First".to_string(),
+                published: None,
+            },
+            story("second"),
+        ];
+        let generated_at = DateTime::parse_from_rfc3339("2024-05-01T08:00:00Z").unwrap().with_timezone(&Utc);
+
+        let view = to_latest_stories_view(&stories, "https://feed.example.com/rss".to_string(), generated_at);
+        let json = serde_json::to_value(&view).unwrap();
+
+        assert_eq!(json["generated_at"], "2024-05-01T08:00:00Z");
+        assert_eq!(json["source"], "https://feed.example.com/rss");
+        assert_eq!(json["stories"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            json["stories"][0],
+            serde_json::json!({
+                "rank": 1,
+                "title": "First",
+                "url": "https://www.example.com/post",
+                "points": null,
+                "comments_url": null,
+                "domain": "example.com",
+            })
+        );
+        assert_eq!(json["stories"][1]["rank"], 2);
+    }
+
+    #[test]
+    fn test_domain_label() {
+        let labels = HashMap::from([("example.com".to_string(), "paywalled".to_string())]);
+
+        assert_eq!(domain_label("https://www.example.com/article", &labels), Some("paywalled"));
+        assert_eq!(domain_label("https://blog.example.com/post", &labels), Some("paywalled"));
+        assert_eq!(domain_label("https://notexample.com/post", &labels), None);
+        assert_eq!(domain_label("https://github.com/rust-lang/rust", &labels), None);
+    }
+
+    #[test]
+    fn test_domain_label_prefers_longest_match() {
+        let labels = HashMap::from([
+            ("example.com".to_string(), "paywalled".to_string()),
+            ("blog.example.com".to_string(), "free".to_string()),
+            ("eng.blog.example.com".to_string(), "engineering".to_string()),
+        ]);
+
+        assert_eq!(domain_label("https://blog.example.com/post", &labels), Some("free"));
+        assert_eq!(domain_label("https://eng.blog.example.com/post", &labels), Some("engineering"));
+        assert_eq!(domain_label("https://news.example.com/post", &labels), Some("paywalled"));
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config_helper::{get_config_or, get_prompt};
+use crate::{chatgpt, content, kagi, metrics};
+
+const DEFAULT_BODY_MAX_TOKENS: usize = 3000;
+const DEFAULT_MAX_INPUT_CHARS: usize = 12000;
+const DEFAULT_PER_ITEM_TIMEOUT_SECS: u64 = 25;
+const DEFAULT_MAX_CONCURRENCY: usize = 5;
+const DEFAULT_MIN_SUMMARY_CHARS: usize = 20;
+const DEFAULT_TIMEZONE: &str = "Asia/Taipei";
+const DEFAULT_ARCHIVE_URL: &str = "https://archive.ph/newest/{url}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryProvider {
+    Kagi,
+    // The article body summarized by ChatGPT after Kagi's summary failed the quality check
+    ChatGptFallback,
+    // Neither produced a usable summary, the text is the apology
+    Unavailable,
+    // The summary didn't finish within summary.per_item_timeout_secs, the text is the notice
+    TimedOut,
+}
+
+impl SummaryProvider {
+    pub fn is_failure(self) -> bool {
+        matches!(self, SummaryProvider::Unavailable | SummaryProvider::TimedOut)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryResult {
+    pub text: String,
+    pub provider: SummaryProvider,
+}
+
+#[derive(Default)]
+struct DailySummaryCache {
+    date: Option<NaiveDate>,
+    summaries: HashMap<String, String>,
+}
+
+// Held across generation, so concurrent broadcasts and previews wait for one ChatGPT call
+static DAILY_SUMMARIES: Lazy<Mutex<DailySummaryCache>> = Lazy::new(|| Mutex::new(DailySummaryCache::default()));
+
+// The daily summary is generated once per key and local day; the first request after
+// midnight in summary.timezone starts over
+pub async fn cached_daily_summary<F, Fut>(key: &str, generate: F) -> String
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = String>,
+{
+    if !get_config_or("summary.cache", true) {
+        return generate().await;
+    }
+    cached_in(&DAILY_SUMMARIES, &SystemClock, configured_timezone(), key, generate).await
+}
+
+async fn cached_in<F, Fut>(cache: &Mutex<DailySummaryCache>, clock: &dyn Clock, timezone: Tz, key: &str, generate: F) -> String
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = String>,
+{
+    let today = local_date(clock.now_utc(), timezone);
+    let mut cache = cache.lock().await;
+    if cache.date != Some(today) {
+        cache.date = Some(today);
+        cache.summaries.clear();
+    }
+    if let Some(summary) = cache.summaries.get(key) {
+        return summary.clone();
+    }
+
+    let summary = generate().await;
+    cache.summaries.insert(key.to_string(), summary.clone());
+    summary
+}
+
+// Warm-ups run a fixed lead before the broadcast time in summary.timezone, taking DST into account
+pub fn next_warmup(now: DateTime<Utc>, timezone: Tz, broadcast_time: NaiveTime, lead: chrono::Duration) -> DateTime<Utc> {
+    now.with_timezone(&timezone)
+        .date_naive()
+        .iter_days()
+        .filter_map(|date| timezone.from_local_datetime(&date.and_time(broadcast_time)).earliest())
+        .map(|broadcast| broadcast.with_timezone(&Utc) - lead)
+        .find(|warmup| *warmup > now)
+        .unwrap()
+}
+
+pub fn configured_timezone() -> Tz {
+    let timezone = get_config_or("summary.timezone", DEFAULT_TIMEZONE.to_string());
+    timezone.parse().unwrap_or_else(|_| {
+        log::warn!("Unknown summary.timezone {:?}, using UTC", timezone);
+        Tz::UTC
+    })
+}
+
+fn local_date(now: DateTime<Utc>, timezone: Tz) -> NaiveDate {
+    now.with_timezone(&timezone).date_naive()
+}
+
+// Summaries run concurrently, and one that hangs is replaced by a notice instead of holding up the rest
+pub async fn summarize_urls(urls: Vec<String>) -> Vec<SummaryResult> {
+    let per_item_timeout = Duration::from_secs(get_config_or("summary.per_item_timeout_secs", DEFAULT_PER_ITEM_TIMEOUT_SECS));
+    let max_concurrency = get_config_or("summary.max_concurrency", DEFAULT_MAX_CONCURRENCY);
+    let fallback = get_prompt("prompt.summary_failed");
+    summarize_all(urls, per_item_timeout, max_concurrency, &fallback, |url| async move { summarize_url(&url).await }).await
+}
+
+// One story with the same timeout and notice, for callers that deliver each summary on its own
+pub async fn summarize_url_in_time(url: String) -> String {
+    summarize_urls(vec![url]).await.remove(0).text
+}
+
+async fn summarize_all<F, Fut>(
+    urls: Vec<String>,
+    per_item_timeout: Duration,
+    max_concurrency: usize,
+    fallback: &str,
+    summarize: F,
+) -> Vec<SummaryResult>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = SummaryResult>,
+{
+    stream::iter(urls)
+        .map(|url| {
+            let summary = timeout(per_item_timeout, summarize(url.clone()));
+            async move {
+                let _in_flight = InFlightSummary::start();
+                summary.await.unwrap_or_else(|_| {
+                    log::warn!("Summary for {} timed out after {:?}", url, per_item_timeout);
+                    SummaryResult { text: fallback.to_string(), provider: SummaryProvider::TimedOut }
+                })
+            }
+        })
+        .buffered(max_concurrency.max(1))
+        .collect()
+        .await
+}
+
+// Tracks summary.max_concurrency saturation for /metrics; the guard also counts summaries that get dropped
+struct InFlightSummary {
+    started: Instant,
+}
+
+impl InFlightSummary {
+    fn start() -> Self {
+        let in_flight = metrics::adjust_gauge(metrics::SUMMARY_IN_FLIGHT, 1);
+        metrics::raise_gauge(metrics::SUMMARY_MAX_IN_FLIGHT, in_flight);
+        InFlightSummary { started: Instant::now() }
+    }
+}
+
+impl Drop for InFlightSummary {
+    fn drop(&mut self) {
+        metrics::adjust_gauge(metrics::SUMMARY_IN_FLIGHT, -1);
+        metrics::increment(metrics::SUMMARIES_TOTAL);
+        metrics::increment_by(metrics::SUMMARY_LATENCY_MS_TOTAL, self.started.elapsed().as_millis() as u64);
+        let average = metrics::get(metrics::SUMMARY_LATENCY_MS_TOTAL) / metrics::get(metrics::SUMMARIES_TOTAL).max(1);
+        metrics::set_gauge(metrics::SUMMARY_AVG_LATENCY_MS, average);
+    }
+}
+
+// With summary.use_archive_fallback, a page that can't be summarized gets another try through
+// an archived copy, which often gets past a paywall
+pub async fn summarize_url(url: &str) -> SummaryResult {
+    let result = summarize_direct(url).await;
+    if !get_config_or("summary.use_archive_fallback", false) {
+        return result;
+    }
+
+    let template = get_config_or("summary.archive_url", DEFAULT_ARCHIVE_URL.to_string());
+    with_archive_fallback(url, result, &template, |archive_url| async move { summarize_direct(&archive_url).await }).await
+}
+
+async fn with_archive_fallback<F, Fut>(url: &str, result: SummaryResult, template: &str, summarize: F) -> SummaryResult
+where
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = SummaryResult>,
+{
+    if result.provider != SummaryProvider::Unavailable {
+        return result;
+    }
+
+    let archive_url = archive_url(template, url);
+    log::info!("Retrying the summary of {} through {}", url, archive_url);
+    let archived = summarize(archive_url).await;
+    if archived.provider == SummaryProvider::Unavailable {
+        log::warn!("No usable archived copy of {}", url);
+        return result;
+    }
+    archived
+}
+
+fn archive_url(template: &str, url: &str) -> String {
+    template.replace("{url}", url)
+}
+
+async fn summarize_direct(url: &str) -> SummaryResult {
+    let summary = kagi::get_kagi_summary(url.to_owned()).await;
+    let fallback = get_config_or("chatgpt.summarize_body", false).then(|| {
+        let url = url.to_owned();
+        async move {
+            summarize_article_body(&url).await.unwrap_or_else(|e| {
+                log::warn!("Article body summary failed for {}: {}", url, e);
+                String::new()
+            })
+        }
+    });
+    ensure_quality(url, summary, fallback, is_low_quality, &get_prompt("prompt.summary_low_quality")).await
+}
+
+// A low quality summary gets one retry with the article body summarizer, then an apology instead
+async fn ensure_quality<Fut>(
+    url: &str,
+    summary: String,
+    fallback: Option<Fut>,
+    is_low_quality: impl Fn(&str) -> bool,
+    apology: &str,
+) -> SummaryResult
+where
+    Fut: Future<Output = String>,
+{
+    if !is_low_quality(&summary) {
+        return SummaryResult { text: summary, provider: SummaryProvider::Kagi };
+    }
+
+    log::warn!("Low quality summary for {}: {}", url, summary);
+    if let Some(fallback) = fallback {
+        let fallback_summary = fallback.await;
+        if !is_low_quality(&fallback_summary) {
+            return SummaryResult { text: fallback_summary, provider: SummaryProvider::ChatGptFallback };
+        }
+    }
+    SummaryResult { text: apology.to_string(), provider: SummaryProvider::Unavailable }
+}
+
+// True when every story timed out or got the low quality apology instead of a summary
+pub fn all_failed(summaries: &[SummaryResult]) -> bool {
+    !summaries.is_empty() && summaries.iter().all(|summary| summary.provider.is_failure())
+}
+
+pub fn is_low_quality(summary: &str) -> bool {
+    let min_chars = get_config_or("summary.min_chars", DEFAULT_MIN_SUMMARY_CHARS);
+    let refusal_phrases: Vec<String> = get_config_or("summary.refusal_phrases", Vec::new());
+    is_low_quality_with(summary, min_chars, &refusal_phrases)
+}
+
+fn is_low_quality_with(summary: &str, min_chars: usize, refusal_phrases: &[String]) -> bool {
+    let summary = summary.trim();
+    if summary == kagi::NO_SUMMARY_FOUND || summary.chars().count() < min_chars {
+        return true;
+    }
+
+    let lowercase = summary.to_lowercase();
+    refusal_phrases.iter().any(|phrase| lowercase.contains(&phrase.to_lowercase()))
+}
+
+async fn summarize_article_body(url: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let text = content::fetch_article_text(url).await?;
+    let max_tokens = get_config_or("chatgpt.body_max_tokens", DEFAULT_BODY_MAX_TOKENS);
+    let max_chars = get_config_or("chatgpt.max_input_chars", DEFAULT_MAX_INPUT_CHARS);
+    let text = content::truncate_chars(&content::truncate_to_token_budget(&text, max_tokens), max_chars);
+    chatgpt::get_article_summary(text).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Held by the tests that run summarize_all, so the summary gauges only move for one of them at a time
+    static SUMMARY_GAUGES: Mutex<()> = Mutex::const_new(());
+
+    #[tokio::test]
+    async fn test_hanging_summary_falls_back() {
+        let _gauges = SUMMARY_GAUGES.lock().await;
+        let urls = vec![
+            "https://example.com/fast-1".to_string(),
+            "https://example.com/hangs".to_string(),
+            "https://example.com/fast-2".to_string(),
+        ];
+
+        let summaries = summarize_all(urls, Duration::from_millis(100), 3, "摘要生成失敗", |url| async move {
+            if url.ends_with("hangs") {
+                std::future::pending::<()>().await;
+            }
+            kagi_summary(format!("Summary of {}", url))
+        })
+        .await;
+
+        assert_eq!(summaries[1].provider, SummaryProvider::TimedOut);
+        assert_eq!(
+            summaries.into_iter().map(|summary| summary.text).collect::<Vec<_>>(),
+            vec![
+                "Summary of https://example.com/fast-1",
+                "摘要生成失敗",
+                "Summary of https://example.com/fast-2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_gauge_moves_during_batch() {
+        let _gauges = SUMMARY_GAUGES.lock().await;
+        let urls: Vec<String> = (1..=4).map(|i| format!("https://example.com/{}", i)).collect();
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let observed = std::sync::Mutex::new(Vec::new());
+        metrics::set_gauge(metrics::SUMMARY_MAX_IN_FLIGHT, 0);
+        let in_flight_before = metrics::get_gauge(metrics::SUMMARY_IN_FLIGHT);
+        let total_before = metrics::get(metrics::SUMMARIES_TOTAL);
+
+        summarize_all(urls, Duration::from_secs(1), 2, "摘要生成失敗", |url| {
+            let (running, max_running, observed) = (&running, &max_running, &observed);
+            async move {
+                max_running.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                observed.lock().unwrap().push(metrics::get_gauge(metrics::SUMMARY_IN_FLIGHT) - in_flight_before);
+                running.fetch_sub(1, Ordering::SeqCst);
+                kagi_summary(url)
+            }
+        })
+        .await;
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert!(observed.lock().unwrap().iter().all(|in_flight| (1..=2).contains(in_flight)));
+        assert_eq!(metrics::get_gauge(metrics::SUMMARY_MAX_IN_FLIGHT), in_flight_before + 2);
+        // All four guards dropped, so the gauge is back where it started
+        assert_eq!(metrics::get_gauge(metrics::SUMMARY_IN_FLIGHT), in_flight_before);
+        assert_eq!(metrics::get(metrics::SUMMARIES_TOTAL), total_before + 4);
+    }
+
+    fn kagi_summary(text: String) -> SummaryResult {
+        SummaryResult { text, provider: SummaryProvider::Kagi }
+    }
+
+    #[tokio::test]
+    async fn test_all_failed_summaries() {
+        let _gauges = SUMMARY_GAUGES.lock().await;
+        let urls = vec!["https://example.com/1".to_string(), "https://example.com/2".to_string()];
+
+        let summaries = summarize_all(urls.clone(), Duration::from_millis(50), 2, "摘要生成失敗", |url| async move {
+            if url.ends_with('1') {
+                std::future::pending::<()>().await;
+            }
+            SummaryResult { text: "抱歉，這篇文章無法摘要。".to_string(), provider: SummaryProvider::Unavailable }
+        })
+        .await;
+        assert!(all_failed(&summaries));
+
+        // A real summary that happens to match a notice still counts as a summary
+        let summaries = summarize_all(urls, Duration::from_millis(50), 2, "摘要生成失敗", |_| async move {
+            kagi_summary("摘要生成失敗".to_string())
+        })
+        .await;
+        assert!(!all_failed(&summaries));
+        assert!(!all_failed(&[]));
+    }
+
+    #[tokio::test]
+    async fn test_refusal_summary_triggers_fallback() {
+        let phrases = vec!["I cannot access".to_string()];
+        let check = |summary: &str| is_low_quality_with(summary, 20, &phrases);
+        let url = "https://example.com/paywalled";
+
+        let summary = ensure_quality(
+            url,
+            "I cannot access this page.".to_string(),
+            Some(async { "The article explains how the new scheduler works.".to_string() }),
+            check,
+            "抱歉，這篇文章無法摘要。",
+        )
+        .await;
+        assert_eq!(summary.text, "The article explains how the new scheduler works.");
+        assert_eq!(summary.provider, SummaryProvider::ChatGptFallback);
+
+        let summary = ensure_quality(
+            url,
+            "I cannot access this page.".to_string(),
+            Some(async { "Sorry, I cannot access it.".to_string() }),
+            check,
+            "抱歉，這篇文章無法摘要。",
+        )
+        .await;
+        assert_eq!(summary.text, "抱歉，這篇文章無法摘要。");
+        assert_eq!(summary.provider, SummaryProvider::Unavailable);
+
+        let summary = ensure_quality(
+            url,
+            "The article explains how the new scheduler works.".to_string(),
+            None::<std::future::Ready<String>>,
+            check,
+            "抱歉，這篇文章無法摘要。",
+        )
+        .await;
+        assert_eq!(summary.provider, SummaryProvider::Kagi);
+    }
+
+    #[test]
+    fn test_is_low_quality() {
+        let phrases = vec!["unable to summarize".to_string()];
+
+        assert!(is_low_quality_with("Too short.", 20, &phrases));
+        assert!(is_low_quality_with(kagi::NO_SUMMARY_FOUND, 10, &phrases));
+        assert!(is_low_quality_with("I am Unable To Summarize the content of this page.", 20, &phrases));
+        assert!(!is_low_quality_with("The article explains how the new scheduler works.", 20, &phrases));
+    }
+
+    #[tokio::test]
+    async fn test_daily_summary_regenerates_after_local_midnight() {
+        let cache = Mutex::new(DailySummaryCache::default());
+        let taipei: Tz = "Asia/Taipei".parse().unwrap();
+        // 23:59 in Taipei
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2026, 10, 16, 15, 59, 0).unwrap());
+
+        let first = cached_in(&cache, &clock, taipei, "zh-tw", || async { "昨天的摘要".to_string() }).await;
+        let cached = cached_in(&cache, &clock, taipei, "zh-tw", || async { "不該重新產生".to_string() }).await;
+        clock.advance(Duration::from_secs(60));
+        let regenerated = cached_in(&cache, &clock, taipei, "zh-tw", || async { "今天的摘要".to_string() }).await;
+
+        assert_eq!(first, "昨天的摘要");
+        assert_eq!(cached, "昨天的摘要");
+        assert_eq!(regenerated, "今天的摘要");
+        assert_eq!(cache.lock().await.date, NaiveDate::from_ymd_opt(2026, 10, 17));
+    }
+
+    #[tokio::test]
+    async fn test_paywalled_page_is_summarized_from_archive() {
+        use warp::Filter;
+
+        let paywalled = warp::path("paywalled").map(|| warp::reply::with_status("Subscribe to read", warp::http::StatusCode::FORBIDDEN));
+        let archive = warp::path("newest").map(|| warp::reply::html("<article><p>The archived copy of the article.</p></article>"));
+        let (addr, server) = warp::serve(paywalled.or(archive)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // Stands in for Kagi: the summary is the fetched article text
+        let summarize = |url: String| async move {
+            match content::fetch_article_text(&url).await.map_err(|e| e.to_string()) {
+                Ok(text) => SummaryResult { text, provider: SummaryProvider::Kagi },
+                Err(_) => SummaryResult { text: "抱歉".to_string(), provider: SummaryProvider::Unavailable },
+            }
+        };
+        let url = format!("http://{}/paywalled", addr);
+        let direct = summarize(url.clone()).await;
+        assert_eq!(direct.provider, SummaryProvider::Unavailable);
+
+        let template = format!("http://{}/newest/{{url}}", addr);
+        let summary = with_archive_fallback(&url, direct, &template, summarize).await;
+        assert_eq!(summary.text, "The archived copy of the article.");
+
+        // An archive without the page keeps the apology
+        let missing = format!("http://{}/missing/{{url}}", addr);
+        let direct = summarize(url.clone()).await;
+        let summary = with_archive_fallback(&url, direct, &missing, summarize).await;
+        assert_eq!(summary, SummaryResult { text: "抱歉".to_string(), provider: SummaryProvider::Unavailable });
+    }
+
+    #[test]
+    fn test_next_warmup_before_broadcast_time() {
+        let taipei: Tz = "Asia/Taipei".parse().unwrap();
+        let broadcast_time = NaiveTime::from_hms_opt(8, 0, 0).unwrap();
+        let lead = chrono::Duration::minutes(15);
+
+        // 07:00 in Taipei warms up at 07:45 the same day
+        let morning = Utc.with_ymd_and_hms(2026, 10, 15, 23, 0, 0).unwrap();
+        assert_eq!(next_warmup(morning, taipei, broadcast_time, lead), Utc.with_ymd_and_hms(2026, 10, 15, 23, 45, 0).unwrap());
+
+        // 07:50 is past the warm-up, so the next one is tomorrow
+        let late = Utc.with_ymd_and_hms(2026, 10, 15, 23, 50, 0).unwrap();
+        assert_eq!(next_warmup(late, taipei, broadcast_time, lead), Utc.with_ymd_and_hms(2026, 10, 16, 23, 45, 0).unwrap());
+    }
+}
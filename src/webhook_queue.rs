@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::metrics;
+
+// Webhook events wait here for a fixed pool of workers, so a burst of events can't fan out
+// into an unbounded number of ChatGPT, Kagi and LINE calls
+pub struct WebhookQueue<T> {
+    sender: mpsc::Sender<T>,
+}
+
+impl<T: Send + 'static> WebhookQueue<T> {
+    pub fn start<F, Fut>(workers: usize, capacity: usize, process: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let process = Arc::new(process);
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let process = process.clone();
+            tokio::spawn(async move {
+                loop {
+                    let event = receiver.lock().await.recv().await;
+                    let Some(event) = event else {
+                        break;
+                    };
+                    // Each event runs in its own task so a panic ends that event, not the worker
+                    if let Err(e) = tokio::spawn(process(event)).await {
+                        log::error!("Webhook event processing panicked: {}", e);
+                    }
+                }
+            });
+        }
+
+        WebhookQueue { sender }
+    }
+
+    // Never waits: a full queue drops the event, and LINE still gets a 200 so it doesn't retry
+    pub fn enqueue(&self, event: T) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Webhook queue is full, dropping the event");
+                metrics::increment(metrics::WEBHOOK_SHED_TOTAL);
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::error!("Webhook queue is closed, dropping the event");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_workers_process_events() {
+        let processed = Arc::new(StdMutex::new(Vec::new()));
+        let recorded = processed.clone();
+        let queue = WebhookQueue::start(2, 10, move |body: Bytes| {
+            let recorded = recorded.clone();
+            async move {
+                recorded.lock().unwrap().push(String::from_utf8(body.to_vec()).unwrap());
+            }
+        });
+
+        for event in ["a", "b", "c"] {
+            assert!(queue.enqueue(Bytes::from(event)));
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while processed.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut processed = processed.lock().unwrap().clone();
+        processed.sort();
+        assert_eq!(processed, vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_event_keeps_worker_alive() {
+        let (processed_tx, mut processed_rx) = mpsc::unbounded_channel();
+        let queue = WebhookQueue::start(1, 10, move |body: Bytes| {
+            let processed = processed_tx.clone();
+            async move {
+                if body == "bad" {
+                    panic!("malformed event");
+                }
+                processed.send(body).unwrap();
+            }
+        });
+
+        assert!(queue.enqueue(Bytes::from("bad")));
+        assert!(queue.enqueue(Bytes::from("good")));
+
+        let processed = tokio::time::timeout(Duration::from_secs(1), processed_rx.recv()).await.unwrap();
+        assert_eq!(processed.unwrap(), "good");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_sheds_instead_of_blocking() {
+        let release = Arc::new(Semaphore::new(0));
+        let (started_tx, mut started_rx) = mpsc::unbounded_channel();
+        let worker_release = release.clone();
+        let queue = WebhookQueue::start(1, 1, move |body: Bytes| {
+            let release = worker_release.clone();
+            let started = started_tx.clone();
+            async move {
+                started.send(body).unwrap();
+                release.acquire().await.unwrap().forget();
+            }
+        });
+
+        assert!(queue.enqueue(Bytes::from("busy")));
+        assert_eq!(started_rx.recv().await.unwrap(), "busy");
+        assert!(queue.enqueue(Bytes::from("queued")));
+        assert!(!queue.enqueue(Bytes::from("shed")));
+
+        release.add_permits(2);
+        assert_eq!(started_rx.recv().await.unwrap(), "queued");
+    }
+}